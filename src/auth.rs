@@ -0,0 +1,48 @@
+//! Custom SOCKS5 authentication methods.
+
+use crate::Error;
+use futures::Future;
+use std::sync::Arc;
+use tokio_tcp::TcpStream;
+
+/// A `Future` which resolves to the connection to the proxy server once the
+/// sub-negotiation of an authentication method has succeeded.
+pub type AuthFuture<S = TcpStream> = Box<dyn Future<Item = S, Error = Error> + Send>;
+
+/// A SOCKS5 authentication method which is not built into this crate.
+///
+/// The no authentication and username/password methods are handled by the `connect` and
+/// `connect_with_password` family of functions. Other methods, e.g. proprietary schemes of
+/// a proxy provider, can be implemented with this trait and passed to
+/// `Socks5Stream::connect_with_auth` or `Socks5Listener::bind_with_auth`.
+///
+/// `S` is the connection to the proxy server, which is a `TcpStream` unless the method is
+/// used on an established connection, e.g. with `Socks5Stream::connect_with_socket_and_auth`.
+pub trait AuthMethod<S = TcpStream>: Send + Sync {
+    /// Returns the method ID offered to the proxy server in the method selection message.
+    ///
+    /// It must not be 0x00, 0x02 or 0xff, which are the IDs of the built-in methods and of
+    /// "no acceptable methods".
+    fn id(&self) -> u8;
+
+    /// Performs the sub-negotiation after the proxy server has selected this method.
+    ///
+    /// The returned future should resolve to `tcp` once the proxy server has accepted the
+    /// authentication, so the request can be sent on it.
+    fn authenticate(&self, tcp: S) -> AuthFuture<S>;
+}
+
+/// An authentication method offered by `Socks5Stream::connect_with_methods`.
+pub enum Method<'a, S = TcpStream> {
+    /// No authentication.
+    None,
+    /// The username/password authentication of RFC 1929.
+    Password {
+        /// The username, which should be 1 to 255 bytes long.
+        username: &'a [u8],
+        /// The password, which should be 1 to 255 bytes long.
+        password: &'a [u8],
+    },
+    /// A custom authentication method.
+    Custom(Arc<dyn AuthMethod<S>>),
+}
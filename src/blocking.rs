@@ -0,0 +1,305 @@
+//! A blocking SOCKS5 client which does not need a runtime.
+//!
+//! It speaks the same protocol as `tcp::Socks5Stream`, over a `std::net::TcpStream`.
+
+use crate::{
+    tcp::{self, Command, HandshakeInfo, NegotiatedAuth},
+    Authentication, Error, IntoTargetAddr, Result, TargetAddr,
+};
+use derefable::Derefable;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+
+/// A blocking SOCKS5 client.
+///
+/// For convenience, it can be dereferenced to `std::net::TcpStream`.
+#[derive(Debug, Derefable)]
+pub struct Socks5Stream {
+    #[deref(mutable)]
+    tcp: TcpStream,
+    info: HandshakeInfo,
+}
+
+impl Socks5Stream {
+    /// Connects to a target server through a SOCKS5 proxy.
+    ///
+    /// The proxy addresses are tried in order until a connection is established. If the
+    /// proxy server replies with "TTL expired", the next proxy address is tried once more;
+    /// use `Connector` to turn that off.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`
+    /// and the errors of the negotiation.
+    pub fn connect<'t, P, T>(proxy: P, target: T) -> Result<Socks5Stream>
+    where
+        P: ToSocketAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Connector::new().connect_raw(proxy, target, Authentication::None)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using given username and
+    /// password.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`
+    /// and the errors of the negotiation.
+    pub fn connect_with_password<'t, P, T>(
+        proxy: P,
+        target: T,
+        username: &str,
+        password: &str,
+    ) -> Result<Socks5Stream>
+    where
+        P: ToSocketAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Self::connect_with_password_bytes(proxy, target, username.as_bytes(), password.as_bytes())
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using given username and
+    /// password as raw bytes.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`
+    /// and the errors of the negotiation.
+    pub fn connect_with_password_bytes<'t, P, T>(
+        proxy: P,
+        target: T,
+        username: &[u8],
+        password: &[u8],
+    ) -> Result<Socks5Stream>
+    where
+        P: ToSocketAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Connector::new().connect_raw(
+            proxy,
+            target,
+            Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
+        )
+    }
+
+    /// Consumes the `Socks5Stream`, returning the inner `std::net::TcpStream`.
+    pub fn into_inner(self) -> TcpStream {
+        self.tcp
+    }
+
+    /// Returns the information about the negotiation with the proxy server.
+    pub fn handshake_info(&self) -> &HandshakeInfo {
+        &self.info
+    }
+
+    /// Returns the authentication method selected by the proxy server.
+    pub fn negotiated_auth(&self) -> NegotiatedAuth {
+        self.info.auth
+    }
+
+    /// Returns the target address that the proxy server connects to.
+    pub fn target_addr(&self) -> TargetAddr<'_> {
+        match &self.info.target {
+            TargetAddr::Ip(addr) => TargetAddr::Ip(*addr),
+            TargetAddr::Domain(domain, port) => TargetAddr::Domain(domain.as_ref().into(), *port),
+        }
+    }
+
+    /// Returns the address of the proxy server this stream is connected to.
+    pub fn proxy_addr(&self) -> SocketAddr {
+        self.info.proxy_addr
+    }
+}
+
+/// Settings of the negotiation for connections which need more than `Socks5Stream::connect`
+/// offers.
+#[derive(Debug, Clone)]
+pub struct Connector {
+    retry_on_ttl_expired: bool,
+}
+
+impl Default for Connector {
+    fn default() -> Self {
+        Connector {
+            retry_on_ttl_expired: true,
+        }
+    }
+}
+
+impl Connector {
+    /// Creates a `Connector` with the settings `Socks5Stream::connect` uses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether the next proxy address is tried once more when the proxy server replies
+    /// with "TTL expired", which is often a routing blip behind the proxy. It is on by
+    /// default.
+    pub fn retry_on_ttl_expired(mut self, enabled: bool) -> Self {
+        self.retry_on_ttl_expired = enabled;
+        self
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`
+    /// and the errors of the negotiation.
+    pub fn connect<'t, P, T>(&self, proxy: P, target: T) -> Result<Socks5Stream>
+    where
+        P: ToSocketAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        self.connect_raw(proxy, target, Authentication::None)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using given username and
+    /// password.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`
+    /// and the errors of the negotiation.
+    pub fn connect_with_password<'t, P, T>(
+        &self,
+        proxy: P,
+        target: T,
+        username: &str,
+        password: &str,
+    ) -> Result<Socks5Stream>
+    where
+        P: ToSocketAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        self.connect_raw(
+            proxy,
+            target,
+            Authentication::Password {
+                username: username.as_bytes().into(),
+                password: password.as_bytes().into(),
+            },
+        )
+    }
+
+    fn connect_raw<'t, P, T>(
+        &self,
+        proxy: P,
+        target: T,
+        auth: Authentication,
+    ) -> Result<Socks5Stream>
+    where
+        P: ToSocketAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        tcp::validate_auth(&auth)?;
+        let target = target.into_target_addr()?;
+        let mut ttl_expired = false;
+        for proxy_addr in proxy.to_socket_addrs()? {
+            let mut tcp = match TcpStream::connect(proxy_addr) {
+                Ok(tcp) => tcp,
+                Err(_) => continue,
+            };
+            match handshake(&mut tcp, &auth, Command::Connect, &target) {
+                Ok((auth, bound)) => {
+                    return Ok(Socks5Stream {
+                        tcp,
+                        info: HandshakeInfo {
+                            proxy_addr,
+                            requested_target: target.to_owned(),
+                            target: bound,
+                            auth,
+                        },
+                    })
+                }
+                Err(Error::TtlExpired) if self.retry_on_ttl_expired && !ttl_expired => {
+                    ttl_expired = true
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if ttl_expired {
+            Err(Error::TtlExpired)
+        } else {
+            Err(Error::ProxyServerUnreachable)
+        }
+    }
+}
+
+/// Performs the SOCKS5 negotiation on `tcp`, returning the address bound by the proxy.
+fn handshake(
+    tcp: &mut TcpStream,
+    auth: &Authentication,
+    command: Command,
+    target: &TargetAddr,
+) -> Result<(NegotiatedAuth, TargetAddr<'static>)> {
+    let mut buf = [0; 513];
+
+    let len = tcp::encode_method_selection(auth, &mut buf);
+    tcp.write_all(&buf[..len])?;
+    tcp.read_exact(&mut buf[..2])?;
+    let sub_negotiation = tcp::check_method_selection(auth, &buf[..2])?;
+    let negotiated = NegotiatedAuth::from_method(buf[1]);
+    if sub_negotiation {
+        let len = tcp::encode_password_auth(auth, &mut buf);
+        tcp.write_all(&buf[..len])?;
+        tcp.read_exact(&mut buf[..2])?;
+        tcp::check_password_auth(&buf[..2])?;
+    }
+
+    let len = tcp::encode_request(command, target, &mut buf);
+    tcp.write_all(&buf[..len])?;
+    tcp.read_exact(&mut buf[..4])?;
+    tcp::check_reply(&buf[..4])?;
+    let (ptr, len) = match buf[3] {
+        // IPv4
+        0x01 => (4, 10),
+        // IPv6
+        0x04 => (4, 22),
+        // Domain
+        0x03 => {
+            tcp.read_exact(&mut buf[4..5])?;
+            (5, buf[4] as usize + 7)
+        }
+        atyp => Err(Error::UnknownAddressType(atyp))?,
+    };
+    tcp.read_exact(&mut buf[ptr..len])?;
+    let bound = tcp::parse_address(&buf[3..len])?;
+    Ok((negotiated, bound))
+}
+
+impl Read for Socks5Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tcp.read(buf)
+    }
+}
+
+impl Write for Socks5Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tcp.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+impl Read for &Socks5Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.tcp).read(buf)
+    }
+}
+
+impl Write for &Socks5Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.tcp).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.tcp).flush()
+    }
+}
@@ -0,0 +1,154 @@
+//! Connections through several SOCKS5 proxies in a row.
+
+use crate::{
+    connector::{BoxConnectFuture, ProxyConnect, ProxyStream},
+    tcp::{validate_auth, Command, SocketConnectFuture, Socks5Stream},
+    Authentication, Error, Result, TargetAddr,
+};
+use futures::{future, Future};
+use std::net::SocketAddr;
+
+/// A `Future` returned by `ProxyChain::connect`.
+pub type ChainConnectFuture =
+    Box<dyn Future<Item = Socks5Stream<Box<dyn ProxyStream>>, Error = Error> + Send>;
+
+/// A chain of SOCKS5 proxies which connections go through in order.
+///
+/// The first proxy is connected to directly and asked to connect to the second one, the
+/// negotiation with the second proxy runs through that tunnel, and so on until the last
+/// proxy connects to the target.
+#[derive(Debug)]
+pub struct ProxyChain {
+    first: SocketAddr,
+    first_credentials: Option<(String, String)>,
+    /// The proxies after the first one, in order.
+    hops: Vec<Hop>,
+}
+
+#[derive(Debug)]
+struct Hop {
+    addr: TargetAddr<'static>,
+    credentials: Option<(String, String)>,
+}
+
+impl ProxyChain {
+    /// Creates a chain which starts with the given SOCKS5 proxy.
+    pub fn new(first: SocketAddr) -> Self {
+        ProxyChain {
+            first,
+            first_credentials: None,
+            hops: Vec::new(),
+        }
+    }
+
+    /// Creates a chain which starts with the given SOCKS5 proxy using given username and
+    /// password.
+    pub fn with_password<U, P>(first: SocketAddr, username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        let mut chain = Self::new(first);
+        chain.first_credentials = Some((username.into(), password.into()));
+        chain
+    }
+
+    /// Appends a SOCKS5 proxy, which the previous proxy connects to.
+    ///
+    /// The address may be a domain name, which is resolved by the previous proxy.
+    pub fn hop(mut self, addr: TargetAddr<'static>) -> Self {
+        self.hops.push(Hop {
+            addr,
+            credentials: None,
+        });
+        self
+    }
+
+    /// Appends a SOCKS5 proxy, which the previous proxy connects to, using given username
+    /// and password.
+    pub fn hop_with_password<U, P>(
+        mut self,
+        addr: TargetAddr<'static>,
+        username: U,
+        password: P,
+    ) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.hops.push(Hop {
+            addr,
+            credentials: Some((username.into(), password.into())),
+        });
+        self
+    }
+
+    /// Connects to `target` through every proxy of the chain.
+    ///
+    /// The returned stream is connected through the last proxy, so its handshake
+    /// information is about the last negotiation, and it wraps the tunnels through the
+    /// previous proxies.
+    ///
+    /// # Error
+    ///
+    /// The future fails with the error of the first negotiation which fails, or right away
+    /// if a username or password is invalid.
+    pub fn connect(&self, target: TargetAddr<'static>) -> ChainConnectFuture {
+        let first_auth = match password_auth(&self.first_credentials) {
+            Ok(auth) => auth,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let mut auths = Vec::with_capacity(self.hops.len());
+        for hop in &self.hops {
+            match password_auth(&hop.credentials) {
+                Ok(auth) => auths.push(auth),
+                Err(e) => return Box::new(future::err(e)),
+            }
+        }
+        let mut targets = self
+            .hops
+            .iter()
+            .map(|hop| hop.addr.to_owned())
+            .chain(Some(target));
+
+        let conn = Socks5Stream::connect_raw(
+            self.first,
+            targets.next().unwrap(),
+            first_auth,
+            Command::Connect,
+        );
+        let mut conn: ChainConnectFuture = match conn {
+            Ok(conn) => Box::new(conn.map(|stream| {
+                let (tcp, info) = stream.into_parts();
+                Socks5Stream::from_parts(Box::new(tcp) as Box<dyn ProxyStream>, info)
+            })),
+            Err(e) => return Box::new(future::err(e)),
+        };
+        for (auth, target) in auths.into_iter().zip(targets) {
+            conn = Box::new(conn.and_then(move |stream| {
+                SocketConnectFuture::new(Box::new(stream) as Box<dyn ProxyStream>, auth, target)
+            }));
+        }
+        conn
+    }
+}
+
+/// Returns the authentication for a proxy of the chain, which is used on a connection of
+/// type `S`.
+fn password_auth<S>(credentials: &Option<(String, String)>) -> Result<Authentication<'static, S>> {
+    let auth = match credentials {
+        Some((username, password)) => Authentication::Password {
+            username: username.clone().into_bytes().into(),
+            password: password.clone().into_bytes().into(),
+        },
+        None => Authentication::None,
+    };
+    validate_auth(&auth)?;
+    Ok(auth)
+}
+
+impl ProxyConnect for ProxyChain {
+    fn connect(&self, target: TargetAddr<'static>) -> BoxConnectFuture {
+        Box::new(ProxyChain::connect(self, target).map(|stream| Box::new(stream) as _))
+    }
+}
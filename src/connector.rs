@@ -0,0 +1,336 @@
+//! Object-safe connectors which can be swapped at runtime.
+
+use crate::{
+    tcp::{Command, Socks5Stream},
+    Authentication, Credentials, Error, Result, TargetAddr,
+};
+use futures::{future, Async, Future};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tcp::TcpStream;
+use tokio_timer::{timeout, Timeout};
+
+/// A stream returned by a `ProxyConnect` implementation.
+pub trait ProxyStream: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send> ProxyStream for T {}
+
+/// A `Future` returned by `ProxyConnect::connect`.
+pub type BoxConnectFuture = Box<dyn Future<Item = Box<dyn ProxyStream>, Error = Error> + Send>;
+
+/// A boxed `ProxyConnect` which can be shared between threads.
+pub type DynConnector = Box<dyn ProxyConnect + Send + Sync>;
+
+/// A trait for objects which can connect to a target server through some kind of proxy.
+///
+/// The trait is object-safe, so different connectors can be chosen at runtime behind a
+/// `DynConnector`.
+pub trait ProxyConnect {
+    /// Connects to `target`, returning a boxed stream to it.
+    fn connect(&self, target: TargetAddr<'static>) -> BoxConnectFuture;
+
+    /// Wraps the connector so that `f` rewrites every target before connecting to it.
+    ///
+    /// It can be used to implement host aliasing, port rewriting or canary routing.
+    fn map_target<F>(self, f: F) -> MapTarget<Self, F>
+    where
+        Self: Sized,
+        F: Fn(TargetAddr<'static>) -> TargetAddr<'static>,
+    {
+        MapTarget { inner: self, f }
+    }
+}
+
+impl<T: ProxyConnect + ?Sized> ProxyConnect for Box<T> {
+    fn connect(&self, target: TargetAddr<'static>) -> BoxConnectFuture {
+        (**self).connect(target)
+    }
+}
+
+/// A `ProxyConnect` which rewrites targets before passing them to another connector.
+///
+/// This `struct` is created by the `map_target` method on `ProxyConnect`.
+#[derive(Debug, Clone)]
+pub struct MapTarget<C, F> {
+    inner: C,
+    f: F,
+}
+
+impl<C, F> ProxyConnect for MapTarget<C, F>
+where
+    C: ProxyConnect,
+    F: Fn(TargetAddr<'static>) -> TargetAddr<'static>,
+{
+    fn connect(&self, target: TargetAddr<'static>) -> BoxConnectFuture {
+        self.inner.connect((self.f)(target))
+    }
+}
+
+/// A `ProxyConnect` which connects to targets directly, without a proxy.
+///
+/// It can stand in for a proxy connector behind a `DynConnector`, e.g. for targets which
+/// bypass the proxy. Domain names are resolved locally, like with
+/// `Socks5Connector::resolve_locally`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DirectConnector;
+
+impl ProxyConnect for DirectConnector {
+    fn connect(&self, target: TargetAddr<'static>) -> BoxConnectFuture {
+        let addr: Box<dyn Future<Item = SocketAddr, Error = Error> + Send> = match target {
+            TargetAddr::Ip(addr) => Box::new(future::ok(addr)),
+            target => Box::new(resolve(target)),
+        };
+        Box::new(addr.and_then(|addr| {
+            TcpStream::connect(&addr)
+                .map(|tcp| Box::new(tcp) as Box<dyn ProxyStream>)
+                .map_err(Error::from)
+        }))
+    }
+}
+
+/// A `ProxyConnect` which connects through a SOCKS5 proxy.
+///
+/// The connector is `Send` and `Sync`, and its credentials are shared rather than copied by
+/// its connections and clones, so one connector can serve a multi-threaded runtime.
+#[derive(Debug, Clone)]
+pub struct Socks5Connector {
+    proxy: SocketAddr,
+    credentials: Option<Arc<Credentials>>,
+    resolve_locally: bool,
+}
+
+impl Socks5Connector {
+    /// Creates a connector for the given SOCKS5 proxy.
+    pub fn new(proxy: SocketAddr) -> Self {
+        Socks5Connector {
+            proxy,
+            credentials: None,
+            resolve_locally: false,
+        }
+    }
+
+    /// Creates a connector for the given SOCKS5 proxy using given username and password.
+    pub fn with_password<U, P>(proxy: SocketAddr, username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        Self::with_password_bytes(proxy, username.into(), password.into())
+    }
+
+    /// Creates a connector for the given SOCKS5 proxy using given username and password as
+    /// raw bytes.
+    pub fn with_password_bytes<U, P>(proxy: SocketAddr, username: U, password: P) -> Self
+    where
+        U: Into<Vec<u8>>,
+        P: Into<Vec<u8>>,
+    {
+        Socks5Connector {
+            proxy,
+            credentials: Some(Arc::new(Credentials {
+                username: username.into(),
+                password: password.into(),
+            })),
+            resolve_locally: false,
+        }
+    }
+
+    /// Sets whether domain name targets are resolved locally and sent to the proxy server
+    /// as IP addresses, like `socks5://` as opposed to `socks5h://` in curl.
+    ///
+    /// It is off by default, so the proxy server resolves domain names. Turn it on for proxy
+    /// servers which mishandle domain names. The domain name is resolved with
+    /// `std::net::ToSocketAddrs` when the returned future is polled, inside
+    /// `tokio_threadpool::blocking` so that other tasks of a thread pool runtime keep
+    /// running. On other executors, e.g. a current thread runtime, the lookup blocks the
+    /// thread which polls the future.
+    pub fn resolve_locally(mut self, enabled: bool) -> Self {
+        self.resolve_locally = enabled;
+        self
+    }
+
+    /// Connects to `target` like `ProxyConnect::connect`, with some settings of the
+    /// connector replaced for this connection only.
+    ///
+    /// It saves building a connector per connection when, for example, every request uses
+    /// a new session of a rotating proxy provider.
+    pub fn connect_with(
+        &self,
+        target: TargetAddr<'static>,
+        overrides: Overrides,
+    ) -> BoxConnectFuture {
+        let auth = match (overrides.credentials, &self.credentials) {
+            (Some((username, password)), _) => Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
+            (None, Some(credentials)) => Authentication::SharedPassword(credentials.clone()),
+            (None, None) => Authentication::None,
+        };
+        let proxy = self.proxy;
+        let connect = move |target| {
+            Socks5Stream::connect_raw(proxy, target, auth, Command::Connect)
+                .map(|conn| conn.map(|stream| Box::new(stream) as Box<dyn ProxyStream>))
+        };
+        let conn: BoxConnectFuture = match target {
+            TargetAddr::Domain(..) if self.resolve_locally => Box::new(
+                resolve(target)
+                    .and_then(move |addr| connect(TargetAddr::Ip(addr)))
+                    .flatten(),
+            ),
+            target => Box::new(future::result(connect(target)).flatten()),
+        };
+        match overrides.timeout {
+            Some(timeout) => Box::new(Timeout::new(conn, timeout).map_err(timeout_error)),
+            None => conn,
+        }
+    }
+}
+
+impl ProxyConnect for Socks5Connector {
+    fn connect(&self, target: TargetAddr<'static>) -> BoxConnectFuture {
+        self.connect_with(target, Overrides::default())
+    }
+}
+
+/// Returns a `Future` which resolves `target` to its first address.
+fn resolve(target: TargetAddr<'static>) -> impl Future<Item = SocketAddr, Error = Error> {
+    let lookup = move || -> Result<SocketAddr> {
+        target
+            .to_socket_addrs()?
+            .next()
+            .ok_or(Error::InvalidTargetAddress("domain resolves to no address"))
+    };
+    future::poll_fn(move || match tokio_threadpool::blocking(&lookup) {
+        Ok(Async::Ready(res)) => res.map(Async::Ready),
+        Ok(Async::NotReady) => Ok(Async::NotReady),
+        // Not on a thread pool, so there is nothing to hand the thread over to.
+        Err(_) => lookup().map(Async::Ready),
+    })
+}
+
+/// Converts the error of a connection with `Overrides::timeout`.
+// `io::Error::other`, which the lint suggests, needs a newer compiler than the crate supports.
+#[allow(clippy::io_other_error)]
+fn timeout_error(e: timeout::Error<Error>) -> Error {
+    if e.is_elapsed() {
+        return io::Error::new(
+            io::ErrorKind::TimedOut,
+            "connection through the proxy timed out",
+        )
+        .into();
+    }
+    match e.into_inner() {
+        Some(e) => e,
+        None => io::Error::new(io::ErrorKind::Other, "no timer for the connection timeout").into(),
+    }
+}
+
+/// Settings which replace those of a `Socks5Connector` for a single connection.
+///
+/// A field which is `None` keeps the setting of the connector.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    /// The username and password used instead of the credentials of the connector.
+    pub credentials: Option<(Vec<u8>, Vec<u8>)>,
+    /// The time limit of the whole connection, including the local resolution of the
+    /// target. The connection fails with an `Io` error of kind `TimedOut` when it is up.
+    ///
+    /// The connector itself sets no limit. It needs the timer of a Tokio runtime.
+    pub timeout: Option<Duration>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IntoTargetAddr;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Spawns a proxy server which accepts a CONNECT request without authentication and
+    /// returns the request through the handle.
+    fn proxy() -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = tcp.read(&mut buf).unwrap();
+            tcp.write_all(b"\x05\x00").unwrap();
+            let n = tcp.read(&mut buf).unwrap();
+            tcp.write_all(b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38")
+                .unwrap();
+            buf[..n].to_vec()
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn overridden_timeout_fails_a_slow_connection() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        thread::spawn(move || {
+            // Never answer the greeting.
+            let (_tcp, _) = listener.accept().unwrap();
+            let _ = rx.recv();
+        });
+        let overrides = Overrides {
+            timeout: Some(Duration::from_millis(100)),
+            ..Overrides::default()
+        };
+        let conn =
+            Socks5Connector::new(addr).connect_with("10.0.0.1:80".into_target_addr()?, overrides);
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+        match runtime.block_on(conn) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {}
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        drop(tx);
+        Ok(())
+    }
+
+    #[test]
+    fn connectors_can_be_swapped() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let target = listener.local_addr()?;
+        thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            tcp.write_all(b"direct").unwrap();
+        });
+        let (proxy, handle) = proxy();
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+
+        let mut connector: DynConnector = Box::new(DirectConnector);
+        let stream = runtime.block_on(connector.connect(TargetAddr::Ip(target)))?;
+        let (_, buf) = runtime.block_on(tokio_io::io::read_exact(stream, [0; 6]))?;
+        assert_eq!(&buf, b"direct");
+
+        connector = Box::new(Socks5Connector::new(proxy));
+        runtime.block_on(connector.connect(TargetAddr::Ip(target)))?;
+        assert_eq!(&handle.join().unwrap()[..4], b"\x05\x01\x00\x01");
+        Ok(())
+    }
+
+    #[test]
+    fn domain_is_resolved_locally_on_a_thread_pool() -> Result<()> {
+        let (addr, handle) = proxy();
+        let connector = Socks5Connector::new(addr).resolve_locally(true);
+        let conn = connector.connect(TargetAddr::Domain("localhost".into(), 80));
+        let mut runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(conn)?;
+        let request = handle.join().unwrap();
+        // Either address of localhost, but not the domain name.
+        assert!(
+            request == b"\x05\x01\x00\x01\x7f\0\0\x01\0\x50"
+                || request == b"\x05\x01\x00\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\0\x50",
+            "unexpected request: {:?}",
+            request
+        );
+        Ok(())
+    }
+}
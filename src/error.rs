@@ -1,4 +1,5 @@
 use failure::Fail;
+use std::fmt;
 
 /// Error type of `tokio-socks`
 #[derive(Fail, Debug)]
@@ -16,8 +17,22 @@ pub enum Error {
     #[fail(display = "Proxy server unreachable")]
     ProxyServerUnreachable,
     /// Proxy server returns an invalid version number.
-    #[fail(display = "Invalid response version")]
-    InvalidResponseVersion,
+    #[fail(
+        display = "Invalid response version: expected {:#04x}, found {:#04x}",
+        expected, found
+    )]
+    InvalidResponseVersion {
+        /// The version number defined by the protocol.
+        expected: u8,
+        /// The version number received from the proxy server.
+        found: u8,
+    },
+    /// The server does not speak SOCKS. It contains the protocol the reply looks like.
+    #[fail(display = "Not a SOCKS proxy, the reply looks like {}", looks_like)]
+    NotASocksProxy {
+        /// The protocol the server seems to speak instead.
+        looks_like: DetectedProtocol,
+    },
     /// No acceptable auth methods
     #[fail(display = "No acceptable auth methods")]
     NoAcceptableAuthMethods,
@@ -51,20 +66,107 @@ pub enum Error {
     /// Unknown error
     #[fail(display = "Unknown error")]
     UnknownError,
-    /// Invalid reserved byte
-    #[fail(display = "Invalid reserved byte")]
-    InvalidReservedByte,
-    /// Unknown address type
-    #[fail(display = "Unknown address type")]
-    UnknownAddressType,
+    /// The proxy server replied with an unknown reply code. It contains the received code.
+    #[fail(display = "Unknown reply code: {:#04x}", _0)]
+    UnknownReplyCode(u8),
+    /// Invalid reserved byte. It contains the received byte.
+    #[fail(display = "Invalid reserved byte: {:#04x}", _0)]
+    InvalidReservedByte(u8),
+    /// The proxy server sent more data right after the reply, which strict reply validation
+    /// rejects.
+    #[fail(display = "Unexpected data after the reply")]
+    UnexpectedDataAfterReply,
+    /// Unknown address type. It contains the received address type.
+    #[fail(display = "Unknown address type: {:#04x}", _0)]
+    UnknownAddressType(u8),
+    /// A domain name in a reply is longer than the configured limit. It contains the length
+    /// of the domain name.
+    #[fail(display = "Domain name in the reply is too long: {} bytes", _0)]
+    ReplyDomainTooLong(u8),
     /// Invalid authentication values. It contains the detailed error message.
     #[fail(display = "Invalid auth values: {}", _0)]
     InvalidAuthValues(&'static str),
+    /// Invalid target pattern. It contains the detailed error message.
+    #[fail(display = "Invalid target pattern: {}", _0)]
+    InvalidPattern(&'static str),
+    /// SOCKS4 request rejected or failed
+    #[fail(display = "SOCKS4 request rejected or failed")]
+    Socks4RequestRejected,
+    /// SOCKS4 request rejected because the proxy server cannot connect to identd on the
+    /// client
+    #[fail(display = "SOCKS4 request rejected, identd unreachable")]
+    IdentdUnreachable,
+    /// SOCKS4 request rejected because the user ID does not match the one reported by
+    /// identd
+    #[fail(display = "SOCKS4 request rejected, user ID mismatch")]
+    IdentdUserIdMismatch,
+    /// Invalid response of an echo endpoint. It contains the detailed error message.
+    #[fail(display = "Invalid response of the echo endpoint: {}", _0)]
+    InvalidEchoResponse(&'static str),
+    /// The HTTP proxy server rejected the CONNECT request. It contains the status code.
+    #[fail(display = "HTTP proxy rejected the request, status: {}", _0)]
+    HttpProxyRejected(u16),
+    /// Invalid response of an HTTP proxy server. It contains the detailed error message.
+    #[fail(display = "Invalid response of the HTTP proxy: {}", _0)]
+    InvalidHttpResponse(&'static str),
     /// Password auth failure
     #[fail(display = "Password auth failure, code: {}", _0)]
     PasswordAuthFailure(u8),
 }
 
+impl Error {
+    /// Returns the `REP` field of the SOCKS5 reply if the proxy server rejected the
+    /// request, e.g. `0x02` for `ConnectionNotAllowedByRuleset`.
+    pub fn reply_code(&self) -> Option<u8> {
+        match self {
+            Error::GeneralSocksServerFailure => Some(0x01),
+            Error::ConnectionNotAllowedByRuleset => Some(0x02),
+            Error::NetworkUnreachable => Some(0x03),
+            Error::HostUnreachable => Some(0x04),
+            Error::ConnectionRefused => Some(0x05),
+            Error::TtlExpired => Some(0x06),
+            Error::CommandNotSupported => Some(0x07),
+            Error::AddressTypeNotSupported => Some(0x08),
+            Error::UnknownReplyCode(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// Returns the HTTP status a reverse proxy should answer with when a tunnel fails with
+    /// this error, or `None` if the error is not caused by the proxy or the target, e.g. an
+    /// invalid target address.
+    ///
+    /// Timeouts, including a TTL expired reply, map to 504 Gateway Timeout, and other
+    /// failures of the proxy server or the target, e.g. a refused connection, map to 502
+    /// Bad Gateway.
+    pub fn as_gateway_status(&self) -> Option<u16> {
+        match self {
+            Error::Io(e) if e.kind() == std::io::ErrorKind::TimedOut => Some(504),
+            Error::TtlExpired | Error::HttpProxyRejected(504) => Some(504),
+            Error::ParseError(_)
+            | Error::InvalidTargetAddress(_)
+            | Error::InvalidAuthValues(_)
+            | Error::InvalidPattern(_) => None,
+            _ => Some(502),
+        }
+    }
+}
+
+/// A protocol detected on a server which was expected to be a SOCKS proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedProtocol {
+    /// The server replied with an HTTP response, e.g. it is an HTTP proxy.
+    Http,
+}
+
+impl fmt::Display for DetectedProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DetectedProtocol::Http => f.write_str("HTTP"),
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Error {
         Error::Io(err)
@@ -0,0 +1,148 @@
+//! Discovery of the exit IP address of a proxy.
+//!
+//! Proxy rotation services do not always tell which exit a connection got. The helper
+//! in this module asks an HTTP endpoint which echoes the client address, e.g. one which
+//! replies to `GET /` with the IP address as plain text, through an established tunnel.
+
+use crate::{Error, Result};
+use futures::Future;
+use std::net::IpAddr;
+use std::str;
+use tokio_io::{
+    io::{read_to_end, write_all},
+    AsyncRead, AsyncWrite,
+};
+
+/// The maximum length of the response of an echo endpoint.
+const MAX_RESPONSE_LEN: u64 = 8 * 1024;
+
+/// A plain HTTP endpoint which replies with the IP address of the client as its body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoEndpoint {
+    host: String,
+    path: String,
+}
+
+impl EchoEndpoint {
+    /// Creates an endpoint which is requested with `GET path` and the `Host` header `host`.
+    pub fn new<H, P>(host: H, path: P) -> Self
+    where
+        H: Into<String>,
+        P: Into<String>,
+    {
+        EchoEndpoint {
+            host: host.into(),
+            path: path.into(),
+        }
+    }
+
+    /// Returns the value of the `Host` header.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the requested path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// Asks `endpoint` for the IP address it sees, over `stream`, which should already be
+/// connected to the endpoint through the proxy, e.g. with `Socks5Stream::connect`.
+///
+/// The returned `Future` resolves to the exit IP address of the proxy. It consumes the
+/// stream, since the request asks the endpoint to close the connection.
+///
+/// # Error
+///
+/// The future fails with `InvalidEchoResponse` if the endpoint does not reply with status
+/// 200 and an IP address as the body, or the response is longer than 8 KiB.
+pub fn discover_exit_ip<S>(
+    stream: S,
+    endpoint: &EchoEndpoint,
+) -> impl Future<Item = IpAddr, Error = Error>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: text/plain\r\nConnection: close\r\n\r\n",
+        endpoint.path, endpoint.host
+    );
+    write_all(stream, request)
+        .and_then(|(stream, _)| read_to_end(stream.take(MAX_RESPONSE_LEN + 1), Vec::new()))
+        .map_err(Error::from)
+        .and_then(|(_, response)| parse_response(&response))
+}
+
+/// Parses the IP address out of an HTTP response.
+fn parse_response(response: &[u8]) -> Result<IpAddr> {
+    if response.len() as u64 > MAX_RESPONSE_LEN {
+        Err(Error::InvalidEchoResponse("response is too long"))?
+    }
+    let response = str::from_utf8(response)
+        .map_err(|_| Error::InvalidEchoResponse("not a valid UTF-8 string"))?;
+    let (head, body) = match response.find("\r\n\r\n") {
+        Some(end) => (&response[..end], &response[(end + 4)..]),
+        None => Err(Error::InvalidEchoResponse("incomplete response"))?,
+    };
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1));
+    if status != Some("200") {
+        Err(Error::InvalidEchoResponse("status is not 200"))?
+    }
+    body.trim()
+        .parse()
+        .map_err(|_| Error::InvalidEchoResponse("body is not an IP address"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tokio_tcp::TcpStream;
+
+    #[test]
+    fn parses_echoed_ip() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let handle = thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let n = tcp.read(&mut buf).unwrap();
+            tcp.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n203.0.113.7\n")
+                .unwrap();
+            buf[..n].to_vec()
+        });
+        let endpoint = EchoEndpoint::new("ip.example.com", "/");
+        let fut = TcpStream::connect(&addr)
+            .map_err(Error::from)
+            .and_then(move |tcp| discover_exit_ip(tcp, &endpoint));
+        let ip = tokio::runtime::current_thread::block_on_all(fut)?;
+        assert_eq!(ip, IpAddr::from([203, 0, 113, 7]));
+        let request = handle.join().unwrap();
+        assert!(request.starts_with(b"GET / HTTP/1.0\r\nHost: ip.example.com\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_responses() {
+        for response in &[
+            &b"HTTP/1.1 403 Forbidden\r\n\r\n203.0.113.7"[..],
+            &b"HTTP/1.1 200 OK\r\n\r\n<html></html>"[..],
+            &b"HTTP/1.1 200 OK\r\n"[..],
+        ] {
+            match parse_response(response) {
+                Err(Error::InvalidEchoResponse(_)) => {}
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+        assert_eq!(
+            parse_response(b"HTTP/1.1 200 OK\r\n\r\n2001:db8::1").unwrap(),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
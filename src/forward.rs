@@ -0,0 +1,207 @@
+//! Local port forwarding through a proxy.
+
+#[cfg(feature = "log")]
+use crate::redact::LogAddr;
+use crate::{connector::ProxyConnect, Error, IntoTargetAddr, Result};
+#[cfg(feature = "udp")]
+use crate::{
+    udp::{DatagramFuture, Socks5Datagram, MAX_DATAGRAM_LEN},
+    TargetAddr, ToProxyAddrs,
+};
+#[cfg(feature = "udp")]
+use futures::{try_ready, Async, Poll};
+use futures::{Future, Stream};
+#[cfg(feature = "udp")]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio_io::{
+    io::{copy, shutdown},
+    AsyncRead,
+};
+use tokio_tcp::TcpListener;
+#[cfg(feature = "udp")]
+use tokio_udp::UdpSocket;
+
+/// Forwards every connection accepted by `listener` to `target` through a fresh tunnel
+/// created by `connector`, much like `ssh -L`.
+///
+/// The returned `Future` accepts connections until the listener fails. Each forwarded
+/// connection is spawned onto the default executor, so it must be run on a tokio runtime.
+/// A connection is closed if its tunnel cannot be established.
+///
+/// # Error
+///
+/// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+pub fn forward_tcp<'t, C, T>(
+    listener: TcpListener,
+    connector: C,
+    target: T,
+) -> Result<impl Future<Item = (), Error = Error>>
+where
+    C: ProxyConnect + Send + 'static,
+    T: IntoTargetAddr<'t>,
+{
+    let target = target.into_target_addr()?.to_owned();
+    Ok(listener
+        .incoming()
+        .map_err(Error::from)
+        .for_each(move |local| {
+            debug!(
+                "forwarding {} to {}",
+                LogAddr::from(local.peer_addr().ok()),
+                LogAddr::from(&target)
+            );
+            let relay = connector.connect(target.to_owned()).and_then(|remote| {
+                let (local_reader, local_writer) = local.split();
+                let (remote_reader, remote_writer) = remote.split();
+                let upstream = copy(local_reader, remote_writer).and_then(|(_, _, w)| shutdown(w));
+                let downstream =
+                    copy(remote_reader, local_writer).and_then(|(_, _, w)| shutdown(w));
+                upstream.join(downstream).map(|_| ()).map_err(Error::from)
+            });
+            tokio_executor::spawn(relay.map_err(|_e| {
+                warn!("forwarded connection failed: {}", _e);
+            }));
+            Ok(())
+        }))
+}
+
+/// Forwards the datagrams received by `local` to `target` through a UDP association with
+/// `proxy`, and the datagrams from the target back to the local application.
+///
+/// Replies are sent to the address the last local datagram came from, so an application
+/// which rebinds its socket keeps receiving them. If the proxy server ends the association
+/// by closing the control connection or its relay becomes unreachable, a new association is
+/// set up, bound to the unspecified address of the same family as `local`. Datagrams which
+/// cannot be sent right away to the local application are dropped.
+///
+/// The returned `Future` runs until `local` fails or an association cannot be set up.
+///
+/// # Error
+///
+/// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+#[cfg(feature = "udp")]
+pub fn forward_udp<'t, P, T>(
+    local: UdpSocket,
+    proxy: P,
+    target: T,
+) -> Result<impl Future<Item = (), Error = Error>>
+where
+    P: ToProxyAddrs,
+    T: IntoTargetAddr<'t>,
+{
+    let target = target.into_target_addr()?.to_owned();
+    let bind_addr = match local.local_addr()? {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let conn = Socks5Datagram::bind(&proxy, &bind_addr)?;
+    Ok(ForwardUdp {
+        local,
+        proxy,
+        bind_addr,
+        target,
+        conn: Some(conn),
+        datagram: None,
+        client: None,
+        pending: None,
+        buf: vec![0; MAX_DATAGRAM_LEN],
+        reply: vec![0; MAX_DATAGRAM_LEN],
+    })
+}
+
+#[cfg(feature = "udp")]
+struct ForwardUdp<P: ToProxyAddrs> {
+    local: UdpSocket,
+    proxy: P,
+    bind_addr: SocketAddr,
+    target: TargetAddr<'static>,
+    /// The association being set up, until it becomes `datagram`.
+    conn: Option<DatagramFuture<'static, P::Output>>,
+    datagram: Option<Socks5Datagram>,
+    /// The address of the local application.
+    client: Option<SocketAddr>,
+    /// The length of a local datagram in `buf` which is yet to be sent to the relay.
+    pending: Option<usize>,
+    buf: Vec<u8>,
+    reply: Vec<u8>,
+}
+
+#[cfg(feature = "udp")]
+impl<P: ToProxyAddrs> ForwardUdp<P> {
+    /// Relays datagrams in both directions until neither side is ready. It becomes ready
+    /// when the association has ended, and fails if `local` fails.
+    fn relay(&mut self) -> Poll<(), Error> {
+        let datagram = self.datagram.as_mut().unwrap();
+        if datagram.poll_closed()?.is_ready() {
+            return Ok(Async::Ready(()));
+        }
+        loop {
+            let mut progress = false;
+            if self.pending.is_none() {
+                if let Async::Ready((n, from)) = self.local.poll_recv_from(&mut self.buf)? {
+                    self.client = Some(from);
+                    self.pending = Some(n);
+                }
+            }
+            if let Some(n) = self.pending {
+                match datagram.poll_send_to(&self.buf[..n], self.target.to_owned()) {
+                    Ok(Async::Ready(_)) => {
+                        self.pending = None;
+                        progress = true;
+                    }
+                    Ok(Async::NotReady) => {}
+                    Err(_e) => {
+                        warn!("sending to the UDP relay failed: {}", _e);
+                        return Ok(Async::Ready(()));
+                    }
+                }
+            }
+            match datagram.poll_recv_from(&mut self.reply) {
+                Ok(Async::Ready((n, _))) => {
+                    progress = true;
+                    if let Some(client) = self.client {
+                        match self.local.poll_send_to(&self.reply[..n], &client) {
+                            Ok(Async::Ready(_)) => {}
+                            _ => {
+                                debug!("dropping a UDP reply to {}", LogAddr::from(client));
+                            }
+                        }
+                    }
+                }
+                Ok(Async::NotReady) => {}
+                Err(_e) => {
+                    warn!("receiving from the UDP relay failed: {}", _e);
+                    return Ok(Async::Ready(()));
+                }
+            }
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "udp")]
+impl<P: ToProxyAddrs> Future for ForwardUdp<P> {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        loop {
+            if let Some(conn) = &mut self.conn {
+                let datagram = try_ready!(conn.poll());
+                debug!(
+                    "forwarding UDP through relay {}",
+                    LogAddr::from(datagram.relay_addr())
+                );
+                self.conn = None;
+                self.datagram = Some(datagram);
+            }
+            try_ready!(self.relay());
+            warn!("UDP association ended, associating again");
+            let conn = Socks5Datagram::bind(&self.proxy, &self.bind_addr)?;
+            self.datagram = None;
+            self.conn = Some(conn);
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//! Wrappers around the connection to the proxy server.
+
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tcp::TcpStream;
+
+/// A wrapper applied to the connection to the proxy server before the SOCKS5 negotiation.
+///
+/// It can be used to obfuscate, compress or otherwise transform everything sent to and
+/// received from the proxy server, e.g. for a proxy server behind a shadowsocks-style
+/// wrapper. Pass it to `Socks5Stream::connect_layered`. Closures taking the `TcpStream`
+/// are layers, and layers compose by calling one from another.
+pub trait Layer {
+    /// The wrapped connection.
+    type Output: AsyncRead + AsyncWrite;
+
+    /// Wraps the established connection to the proxy server.
+    fn wrap(&self, tcp: TcpStream) -> Self::Output;
+}
+
+impl<F, S> Layer for F
+where
+    F: Fn(TcpStream) -> S,
+    S: AsyncRead + AsyncWrite,
+{
+    type Output = S;
+
+    fn wrap(&self, tcp: TcpStream) -> S {
+        self(tcp)
+    }
+}
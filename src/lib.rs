@@ -1,5 +1,6 @@
 use either::Either;
 use futures::{
+    future::{self, FutureResult},
     stream::{self, IterOk, Once, Stream},
     Async, Poll,
 };
@@ -8,12 +9,31 @@ use std::{
     io,
     iter::Cloned,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
+    num::NonZeroU16,
     slice::Iter,
+    sync::Arc,
     vec,
 };
+use tokio_tcp::TcpStream;
 
-pub use error::Error;
 use error::Result;
+pub use error::{DetectedProtocol, Error};
+
+/// Emits a `log` record at the debug level if the `log` feature is enabled.
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        log::debug!($($arg)+);
+    };
+}
+
+/// Emits a `log` record at the warn level if the `log` feature is enabled.
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "log")]
+        log::warn!($($arg)+);
+    };
+}
 
 /// A trait for objects which can be converted or resolved to one or more `SocketAddr` values,
 /// which are going to be connected as the the proxy server.
@@ -56,7 +76,18 @@ impl ToProxyAddrs for str {
     type Output = ProxyAddrsStream;
 
     fn to_proxy_addrs(&self) -> Self::Output {
-        ProxyAddrsStream(Some(self.to_socket_addrs()))
+        let scoped = self.rsplitn(2, ':').collect::<Vec<_>>();
+        let scoped = match scoped[..] {
+            [port, host] => port
+                .parse()
+                .ok()
+                .and_then(|port| scoped_socket_addr(host, port)),
+            _ => None,
+        };
+        match scoped {
+            Some(addr) => ProxyAddrsStream(Some(Ok(vec![addr].into_iter()))),
+            None => ProxyAddrsStream(Some(self.to_socket_addrs())),
+        }
     }
 }
 
@@ -64,10 +95,90 @@ impl<'a> ToProxyAddrs for (&'a str, u16) {
     type Output = ProxyAddrsStream;
 
     fn to_proxy_addrs(&self) -> Self::Output {
-        ProxyAddrsStream(Some(self.to_socket_addrs()))
+        match scoped_socket_addr(self.0, self.1) {
+            Some(addr) => ProxyAddrsStream(Some(Ok(vec![addr].into_iter()))),
+            None => ProxyAddrsStream(Some(self.to_socket_addrs())),
+        }
     }
 }
 
+/// Where an address returned by `resolve_proxy` came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrSource {
+    /// The address was configured literally, e.g. `127.0.0.1:1080` or `[fe80::1%eth0]:1080`.
+    Config,
+    /// The address was resolved from a host name with the system resolver.
+    Dns,
+}
+
+/// An address of a proxy server returned by `resolve_proxy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyEndpoint {
+    /// The address of the proxy server.
+    pub addr: SocketAddr,
+    /// Where the address came from.
+    pub source: AddrSource,
+}
+
+/// Resolves a proxy server given as `host:port` to its addresses, without connecting to it.
+///
+/// It is the resolution step of `connect` on its own, so the addresses can be cached,
+/// shuffled or health-checked first, then passed to `connect` as a `&[SocketAddr]`. Like the
+/// `ToProxyAddrs` implementation of `str`, host names are resolved with
+/// `std::net::ToSocketAddrs`, which blocks the current thread.
+pub fn resolve_proxy(proxy: &str) -> FutureResult<Vec<ProxyEndpoint>, Error> {
+    let literal = proxy.parse().ok().or_else(|| {
+        let mut parts = proxy.rsplitn(2, ':');
+        let port = parts.next()?.parse().ok()?;
+        scoped_socket_addr(parts.next()?, port)
+    });
+    let endpoints = match literal {
+        Some(addr) => Ok(vec![ProxyEndpoint {
+            addr,
+            source: AddrSource::Config,
+        }]),
+        None => proxy.to_socket_addrs().map_err(Error::from).map(|addrs| {
+            addrs
+                .map(|addr| ProxyEndpoint {
+                    addr,
+                    source: AddrSource::Dns,
+                })
+                .collect()
+        }),
+    };
+    future::result(endpoints)
+}
+
+/// Parses an IPv6 address with a zone ID, e.g. `fe80::1%eth0` or `[fe80::1%2]`.
+///
+/// The zone ID may be an interface name or a numeric scope ID. It is kept in the scope ID
+/// of the returned address, so link-local proxy servers can be connected to.
+fn scoped_socket_addr(host: &str, port: u16) -> Option<SocketAddr> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut parts = host.splitn(2, '%');
+    let ip = parts.next()?.parse::<Ipv6Addr>().ok()?;
+    let zone = parts.next()?;
+    let scope_id = match zone.parse() {
+        Ok(scope_id) => scope_id,
+        Err(_) => interface_index(zone)?,
+    };
+    Some(SocketAddrV6::new(ip, port, 0, scope_id).into())
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
 impl<'a, T: ToProxyAddrs + ?Sized> ToProxyAddrs for &'a T {
     type Output = T::Output;
 
@@ -95,7 +206,7 @@ impl Stream for ProxyAddrsStream {
 }
 
 /// A SOCKS connection target.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub enum TargetAddr<'a> {
     /// Connect to an IP address.
     Ip(SocketAddr),
@@ -107,6 +218,64 @@ pub enum TargetAddr<'a> {
 }
 
 impl<'a> TargetAddr<'a> {
+    /// The maximum length of an encoded SOCKS5 address: the address type, a length byte,
+    /// a 255 bytes long domain and the port.
+    pub const MAX_ENCODED_LEN: usize = 1 + 1 + 255 + 2;
+
+    /// Returns the length of the SOCKS5 encoding of the address, i.e. `ATYP`, `ADDR` and
+    /// `PORT` of a request or a UDP header.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            TargetAddr::Ip(SocketAddr::V4(_)) => 1 + 4 + 2,
+            TargetAddr::Ip(SocketAddr::V6(_)) => 1 + 16 + 2,
+            TargetAddr::Domain(domain, _) => 1 + 1 + domain.len() + 2,
+        }
+    }
+
+    /// Writes the SOCKS5 encoding of the address into `buf`, returning its length.
+    ///
+    /// # Panics
+    ///
+    /// It panics if `buf` is shorter than `encoded_len`, which never exceeds
+    /// `MAX_ENCODED_LEN`.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        tcp::encode_address(self, buf)
+    }
+
+    /// Returns the port of the target.
+    pub fn port(&self) -> u16 {
+        match self {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        }
+    }
+
+    /// Parses `addr` of the form `host:port` like `IntoTargetAddr for &str`, but never as an
+    /// IP address, so the host is always sent as a domain name, exactly as written.
+    ///
+    /// Some proxy servers, e.g. Tor or anti-censorship proxies, want every target as a
+    /// domain name to decide themselves how to reach it.
+    ///
+    /// # Error
+    ///
+    /// It fails if `addr` has no port or the host is empty or longer than 255 bytes.
+    pub fn domain_always(addr: &'a str) -> Result<TargetAddr<'a>> {
+        let mut parts_iter = addr.rsplitn(2, ':');
+        let port: u16 = parts_iter
+            .next()
+            .and_then(|port_str| port_str.parse().ok())
+            .ok_or(Error::InvalidTargetAddress("invalid address format"))?;
+        let domain = parts_iter
+            .next()
+            .ok_or(Error::InvalidTargetAddress("invalid address format"))?;
+        if domain.is_empty() || domain.len() > 255 {
+            return Err(Error::InvalidTargetAddress(
+                "domain length should between 1 to 255",
+            ));
+        }
+        Ok(TargetAddr::Domain(domain.into(), port))
+    }
+
     /// Creates owned `TargetAddr` by cloning. It is usually used to eliminate the lifetime bound.
     pub fn to_owned(&self) -> TargetAddr<'static> {
         match self {
@@ -116,6 +285,34 @@ impl<'a> TargetAddr<'a> {
             }
         }
     }
+
+    /// Appends `search_domain` to a single-label domain name, e.g. `db` becomes
+    /// `db.prod.internal`. It is useful when the proxy server resolves names without the
+    /// client's DNS search path.
+    ///
+    /// IP addresses, `localhost` and domain names which already contain a dot are returned
+    /// unchanged.
+    ///
+    /// # Error
+    ///
+    /// It fails if the qualified domain name is longer than 255 bytes.
+    pub fn with_search_domain(self, search_domain: &str) -> Result<TargetAddr<'a>> {
+        let search_domain = search_domain.trim_matches('.');
+        match self {
+            TargetAddr::Domain(ref domain, port)
+                if !search_domain.is_empty()
+                    && !domain.contains('.')
+                    && !domain.eq_ignore_ascii_case("localhost") =>
+            {
+                let qualified = format!("{}.{}", domain, search_domain);
+                if qualified.len() > 255 {
+                    return Err(Error::InvalidTargetAddress("overlong domain"));
+                }
+                Ok(TargetAddr::Domain(qualified.into(), port))
+            }
+            addr => Ok(addr),
+        }
+    }
 }
 
 impl<'a> ToSocketAddrs for TargetAddr<'a> {
@@ -154,12 +351,37 @@ trivial_impl_into_target_addr!((Ipv6Addr, u16));
 trivial_impl_into_target_addr!(SocketAddrV4);
 trivial_impl_into_target_addr!(SocketAddrV6);
 
+macro_rules! nonzero_port_impl_into_target_addr {
+    ($t: ty) => {
+        impl<'a> IntoTargetAddr<'a> for ($t, NonZeroU16) {
+            fn into_target_addr(self) -> Result<TargetAddr<'a>> {
+                (self.0, self.1.get()).into_target_addr()
+            }
+        }
+    };
+}
+
+nonzero_port_impl_into_target_addr!(IpAddr);
+nonzero_port_impl_into_target_addr!(Ipv4Addr);
+nonzero_port_impl_into_target_addr!(Ipv6Addr);
+
+impl<'a> IntoTargetAddr<'a> for TargetAddr<'a> {
+    fn into_target_addr(self) -> Result<TargetAddr<'a>> {
+        Ok(self)
+    }
+}
+
 impl<'a> IntoTargetAddr<'a> for (&'a str, u16) {
     fn into_target_addr(self) -> Result<TargetAddr<'a>> {
         // Try IP address first
         if let Ok(addr) = self.0.parse::<IpAddr>() {
             return (addr, self.1).into_target_addr();
         }
+        if self.0.contains('%') {
+            return scoped_socket_addr(self.0, self.1)
+                .map(TargetAddr::Ip)
+                .ok_or(Error::InvalidTargetAddress("invalid IPv6 zone ID"));
+        }
 
         // Treat as domain name
         let len = self.0.as_bytes().len();
@@ -191,6 +413,12 @@ impl<'a> IntoTargetAddr<'a> for &'a str {
     }
 }
 
+impl<'a> IntoTargetAddr<'a> for (&'a str, NonZeroU16) {
+    fn into_target_addr(self) -> Result<TargetAddr<'a>> {
+        (self.0, self.1.get()).into_target_addr()
+    }
+}
+
 impl IntoTargetAddr<'static> for (String, u16) {
     fn into_target_addr(self) -> Result<TargetAddr<'static>> {
         let addr = (self.0.as_str(), self.1).into_target_addr()?;
@@ -202,6 +430,12 @@ impl IntoTargetAddr<'static> for (String, u16) {
     }
 }
 
+impl IntoTargetAddr<'static> for (String, NonZeroU16) {
+    fn into_target_addr(self) -> Result<TargetAddr<'static>> {
+        (self.0, self.1.get()).into_target_addr()
+    }
+}
+
 impl<'a, T> IntoTargetAddr<'a> for &'a T
 where
     T: IntoTargetAddr<'a> + Copy,
@@ -212,30 +446,118 @@ where
 }
 
 /// Authentication methods
-#[derive(Debug)]
-enum Authentication<'a> {
+enum Authentication<'a, S = TcpStream> {
     Password {
-        username: &'a str,
-        password: &'a str,
+        username: Cow<'a, [u8]>,
+        password: Cow<'a, [u8]>,
     },
+    /// Username/password authentication with credentials shared by many connections.
+    SharedPassword(Arc<Credentials>),
+    Custom(Arc<dyn auth::AuthMethod<S>>),
     None,
+    /// Several methods in the order of preference, of which the proxy server selects one.
+    Multiple(Vec<Authentication<'a, S>>),
 }
 
-impl<'a> Authentication<'a> {
+impl<'a, S> Authentication<'a, S> {
     fn id(&self) -> u8 {
         match self {
-            Authentication::Password { .. } => 0x02,
+            Authentication::Password { .. } | Authentication::SharedPassword(_) => 0x02,
+            Authentication::Custom(method) => method.id(),
             Authentication::None => 0x00,
+            // Never sent as such, each of the methods is offered instead.
+            Authentication::Multiple(_) => 0xff,
+        }
+    }
+
+    /// Returns the username and password of the username/password authentication.
+    fn password(&self) -> Option<(&[u8], &[u8])> {
+        match self {
+            Authentication::Password { username, password } => Some((username, password)),
+            Authentication::SharedPassword(credentials) => {
+                Some((&credentials.username, &credentials.password))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns whether `method` has been offered to the proxy server.
+    fn offers(&self, method: u8) -> bool {
+        match self {
+            Authentication::Multiple(methods) => methods.iter().any(|m| m.id() == method),
+            // The greeting offers no authentication along with username/password.
+            Authentication::Password { .. } | Authentication::SharedPassword(_) => {
+                method == 0x00 || method == 0x02
+            }
+            auth => auth.id() == method,
+        }
+    }
+
+    /// Returns the offered method which the proxy server selected, looking into `Multiple`
+    /// without narrowing it, so the whole offer can be sent again on a retry.
+    fn selected(&self, method: u8) -> &Self {
+        match self {
+            Authentication::Multiple(methods) => {
+                methods.iter().find(|m| m.id() == method).unwrap_or(self)
+            }
+            auth => auth,
         }
     }
 }
 
+/// A username and password which are shared instead of cloned for each connection.
+#[derive(Debug)]
+struct Credentials {
+    username: Vec<u8>,
+    password: Vec<u8>,
+}
+
+impl<'a, S> From<auth::Method<'a, S>> for Authentication<'a, S> {
+    fn from(method: auth::Method<'a, S>) -> Self {
+        match method {
+            auth::Method::None => Authentication::None,
+            auth::Method::Password { username, password } => Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
+            auth::Method::Custom(method) => Authentication::Custom(method),
+        }
+    }
+}
+
+pub mod auth;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod chain;
+pub mod connector;
 mod error;
+#[cfg(feature = "exit-ip")]
+pub mod exit_ip;
+pub mod forward;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod layer;
+pub mod matcher;
+#[cfg(feature = "websocket")]
+pub mod message;
+#[cfg(feature = "test-util")]
+pub mod protocol;
+#[cfg(feature = "log")]
+pub mod redact;
 pub mod tcp;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+pub mod tls;
+#[cfg(feature = "tor")]
+pub mod tor;
+#[cfg(feature = "udp")]
+pub mod udp;
+#[cfg(feature = "v4")]
+pub mod v4;
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::Future;
 
     fn to_proxy_addrs<T: ToProxyAddrs>(t: T) -> Result<Vec<SocketAddr>> {
         t.to_proxy_addrs().wait().collect()
@@ -268,6 +590,35 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn errors_map_to_gateway_status() {
+        let timeout = io::Error::from(io::ErrorKind::TimedOut);
+        assert_eq!(Error::Io(timeout).as_gateway_status(), Some(504));
+        assert_eq!(Error::TtlExpired.as_gateway_status(), Some(504));
+        assert_eq!(Error::ConnectionRefused.as_gateway_status(), Some(502));
+        assert_eq!(Error::ProxyServerUnreachable.as_gateway_status(), Some(502));
+        assert_eq!(
+            Error::InvalidTargetAddress("overlong domain").as_gateway_status(),
+            None
+        );
+    }
+
+    #[test]
+    fn resolve_proxy_reports_address_source() -> Result<()> {
+        let res = resolve_proxy("127.0.0.1:1080").wait()?;
+        assert_eq!(
+            &res[..],
+            &[ProxyEndpoint {
+                addr: SocketAddr::from(([127, 0, 0, 1], 1080)),
+                source: AddrSource::Config,
+            }]
+        );
+        let res = resolve_proxy("localhost:1080").wait()?;
+        assert!(!res.is_empty());
+        assert!(res.iter().all(|e| e.source == AddrSource::Dns));
+        Ok(())
+    }
+
     fn into_target_addr<'a, T>(t: T) -> Result<TargetAddr<'a>>
     where
         T: IntoTargetAddr<'a>,
@@ -309,6 +660,111 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn converts_nonzero_port_to_target_addr() -> Result<()> {
+        let port = NonZeroU16::new(443).unwrap();
+        let addr = SocketAddr::from(([1, 1, 1, 1], 443));
+        let res = into_target_addr((addr.ip(), port))?;
+        assert_eq!(TargetAddr::Ip(addr), res);
+        let res = into_target_addr(("www.example.com", port))?;
+        assert_eq!(
+            TargetAddr::Domain(Cow::Borrowed("www.example.com"), 443),
+            res
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn converts_scoped_ipv6_to_target_addr() -> Result<()> {
+        let addr = SocketAddr::from(SocketAddrV6::new("fe80::1".parse().unwrap(), 80, 0, 2));
+        assert_eq!(TargetAddr::Ip(addr), into_target_addr("[fe80::1%2]:80")?);
+        assert_eq!(TargetAddr::Ip(addr), into_target_addr(("fe80::1%2", 80))?);
+        assert!(into_target_addr(("fe80::1%no-such-interface", 80)).is_err());
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn resolves_interface_name_in_zone_id() -> Result<()> {
+        let addr = SocketAddr::from(SocketAddrV6::new("fe80::1".parse().unwrap(), 1080, 0, 1));
+        assert_eq!(TargetAddr::Ip(addr), into_target_addr("[fe80::1%lo]:1080")?);
+        assert_eq!(to_proxy_addrs("[fe80::1%lo]:1080")?, vec![addr]);
+        assert_eq!(to_proxy_addrs(("fe80::1%lo", 1080))?, vec![addr]);
+        Ok(())
+    }
+
+    #[test]
+    fn encodes_target_addr() -> Result<()> {
+        let mut buf = [0; TargetAddr::MAX_ENCODED_LEN];
+        for (addr, encoded) in &[
+            ("1.2.3.4:80", &b"\x01\x01\x02\x03\x04\x00\x50"[..]),
+            (
+                "[::1]:80",
+                &b"\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x00\x50"[..],
+            ),
+            ("example.com:80", &b"\x03\x0bexample.com\x00\x50"[..]),
+        ] {
+            let addr = into_target_addr(*addr)?;
+            assert_eq!(addr.encoded_len(), encoded.len());
+            let len = addr.encode(&mut buf);
+            assert_eq!(&buf[..len], *encoded);
+        }
+        let longest = "a".repeat(255);
+        let addr = into_target_addr((longest.as_str(), 80))?;
+        assert_eq!(addr.encoded_len(), TargetAddr::MAX_ENCODED_LEN);
+        assert_eq!(addr.encode(&mut buf), TargetAddr::MAX_ENCODED_LEN);
+        Ok(())
+    }
+
+    #[test]
+    fn public_types_are_send_and_sync() {
+        fn assert_send<T: Send>() {}
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send::<tcp::ConnectFuture<'static, 'static, ProxyAddrsStream>>();
+        assert_send::<tcp::BindFuture<'static, 'static, ProxyAddrsStream>>();
+        assert_send::<tcp::AcceptFuture>();
+        assert_send::<tcp::HandshakeFuture<'static, 'static>>();
+        assert_send_sync::<tcp::Socks5Stream>();
+        assert_send_sync::<tcp::Socks5Listener>();
+        assert_send_sync::<connector::Socks5Connector>();
+        assert_send_sync::<connector::DirectConnector>();
+        assert_send_sync::<connector::DynConnector>();
+        assert_send_sync::<matcher::TargetMatcher>();
+        #[cfg(feature = "v4")]
+        {
+            assert_send::<v4::Socks4ConnectFuture<'static, 'static, ProxyAddrsStream>>();
+            assert_send::<v4::ConnectAutoFuture<'static, ProxyAddrsStream>>();
+            assert_send_sync::<v4::Socks4Stream>();
+            assert_send_sync::<v4::SocksStream>();
+            assert_send_sync::<v4::Socks4Connector>();
+        }
+        #[cfg(feature = "udp")]
+        {
+            assert_send::<udp::DatagramFuture<'static, ProxyAddrsStream>>();
+            assert_send_sync::<udp::Socks5Datagram>();
+        }
+        #[cfg(feature = "blocking")]
+        assert_send_sync::<blocking::Socks5Stream>();
+        #[cfg(feature = "tor")]
+        assert_send_sync::<tor::TorIsolation>();
+    }
+
+    #[test]
+    fn domain_always_keeps_ip_literals() -> Result<()> {
+        assert_eq!(
+            TargetAddr::domain_always("1.2.3.4:80")?,
+            TargetAddr::Domain("1.2.3.4".into(), 80)
+        );
+        assert_eq!(
+            TargetAddr::domain_always("[::1]:443")?,
+            TargetAddr::Domain("[::1]".into(), 443)
+        );
+        assert!(TargetAddr::domain_always(":80").is_err());
+        assert!(TargetAddr::domain_always("example.com").is_err());
+        Ok(())
+    }
+
     #[test]
     fn converts_domain_to_target_addr() -> Result<()> {
         let domain = "www.example.com:80";
@@ -339,6 +795,40 @@ mod tests {
         assert!(into_target_addr((domain.as_str(), 80)).is_err());
     }
 
+    #[test]
+    fn appends_search_domain_to_single_label_target_addr() -> Result<()> {
+        let res = into_target_addr(("db", 5432))?.with_search_domain("prod.internal.")?;
+        assert_eq!(
+            TargetAddr::Domain(Cow::Borrowed("db.prod.internal"), 5432),
+            res
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_qualified_target_addr_without_search_domain() -> Result<()> {
+        let res = into_target_addr("www.example.com:80")?.with_search_domain("prod.internal")?;
+        assert_eq!(
+            TargetAddr::Domain(Cow::Borrowed("www.example.com"), 80),
+            res
+        );
+        let res = into_target_addr("localhost:80")?.with_search_domain("prod.internal")?;
+        assert_eq!(TargetAddr::Domain(Cow::Borrowed("localhost"), 80), res);
+        let addr = SocketAddr::from(([1, 1, 1, 1], 443));
+        let res = into_target_addr(addr)?.with_search_domain("prod.internal")?;
+        assert_eq!(TargetAddr::Ip(addr), res);
+        Ok(())
+    }
+
+    #[test]
+    fn overlong_search_domain_should_fail() -> Result<()> {
+        let search_domain = format!("{:a<1$}.com", 'a', 300);
+        assert!(into_target_addr(("db", 80))?
+            .with_search_domain(&search_domain)
+            .is_err());
+        Ok(())
+    }
+
     #[test]
     fn addr_with_invalid_port_to_target_addr_should_fail() {
         let addr = "[ffff::1]:65536";
@@ -0,0 +1,393 @@
+//! Matching of target addresses against lists of patterns.
+//!
+//! A `TargetMatcher` can be used to implement allow/deny lists or `NO_PROXY`-style bypass
+//! rules. Supported patterns are:
+//!
+//! - `*`, which matches every target;
+//! - an exact domain name, e.g. `example.com`;
+//! - a wildcard domain name, e.g. `*.example.com` or `.example.com`, which matches
+//!   `example.com` and all of its subdomains;
+//! - an IP address, e.g. `10.1.2.3` or `[::1]`;
+//! - a CIDR block, e.g. `10.0.0.0/8` or `[fe80::]/10`.
+//!
+//! Each pattern may be followed by a port or an inclusive port range, e.g. `example.com:443`
+//! or `10.0.0.0/8:8000-8999`. IPv6 addresses must be enclosed in brackets if a port is given.
+//! A pattern preceded by `!` is a deny rule, which takes precedence over all other patterns.
+
+// `is_some_and`, which the lint suggests, needs a newer compiler than the crate supports.
+#![allow(clippy::unnecessary_map_or)]
+
+use crate::{Error, Result, TargetAddr};
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    hash::Hash,
+    net::IpAddr,
+};
+
+/// Inclusive port ranges. An empty list matches every port.
+type Ports = Vec<(u16, u16)>;
+
+/// A set of patterns which target addresses can be matched against.
+///
+/// A pattern starting with `!`, e.g. `!admin.example.com`, is a deny rule. A target matches
+/// if it matches any of the other patterns and none of the deny rules, so a deny rule wins
+/// over every other pattern regardless of the order they are added in. This allows lists
+/// such as `*.example.com !admin.example.com`.
+///
+/// Lookups do not scan the whole list, so it is fine to load thousands of patterns.
+#[derive(Debug, Default)]
+pub struct TargetMatcher {
+    allow: Patterns,
+    deny: Patterns,
+}
+
+#[derive(Debug, Default)]
+struct Patterns {
+    any: Option<Ports>,
+    domains: HashMap<String, Ports>,
+    suffixes: HashMap<String, Ports>,
+    // Keyed by prefix length, the inner maps hold the masked network addresses.
+    networks: BTreeMap<(bool, u8), HashMap<IpAddr, Ports>>,
+}
+
+impl TargetMatcher {
+    /// Creates an empty `TargetMatcher` which matches nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a `TargetMatcher` from a comma or whitespace separated list of patterns, like
+    /// the value of the `NO_PROXY` environment variable.
+    ///
+    /// # Error
+    ///
+    /// It fails if any of the patterns is invalid.
+    pub fn from_list(list: &str) -> Result<Self> {
+        let mut matcher = Self::new();
+        for pattern in list
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|pattern| !pattern.is_empty())
+        {
+            matcher.add(pattern)?;
+        }
+        Ok(matcher)
+    }
+
+    /// Adds a pattern to the matcher, or a deny rule if it starts with `!`.
+    ///
+    /// # Error
+    ///
+    /// It fails if the pattern is invalid.
+    pub fn add(&mut self, pattern: &str) -> Result<()> {
+        let pattern = pattern.trim();
+        match pattern.strip_prefix('!') {
+            Some(pattern) => self.deny.add(pattern),
+            None => self.allow.add(pattern),
+        }
+    }
+
+    /// Adds a deny rule, i.e. a pattern which targets must not match, to the matcher.
+    ///
+    /// # Error
+    ///
+    /// It fails if the pattern is invalid.
+    pub fn deny(&mut self, pattern: &str) -> Result<()> {
+        self.deny.add(pattern.trim())
+    }
+
+    /// Returns whether the matcher contains no patterns.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Returns whether `target` matches any of the patterns and none of the deny rules.
+    pub fn matches(&self, target: &TargetAddr) -> bool {
+        self.allow.matches(target) && !self.deny.matches(target)
+    }
+}
+
+impl Patterns {
+    fn add(&mut self, pattern: &str) -> Result<()> {
+        let (host, ports) = split_ports(pattern)?;
+        let host = host.trim_end_matches('.');
+        let ports: Ports = ports.into_iter().collect();
+
+        if host == "*" {
+            match &mut self.any {
+                Some(existing) => merge(existing, ports),
+                None => self.any = Some(ports),
+            }
+        } else if let Some((ip, prefix_len)) = parse_network(host)? {
+            let networks = self.networks.entry((ip.is_ipv4(), prefix_len)).or_default();
+            insert(networks, mask(ip, prefix_len), ports);
+        } else {
+            let suffix = host.strip_prefix("*.").or_else(|| host.strip_prefix('.'));
+            let (map, name) = match suffix {
+                Some(name) => (&mut self.suffixes, name),
+                None => (&mut self.domains, host),
+            };
+            if name.is_empty() || name.len() > 255 || name.contains(|c| "*/[]!".contains(c)) {
+                return Err(Error::InvalidPattern("invalid domain name"));
+            }
+            insert(map, name.to_ascii_lowercase(), ports);
+        }
+        Ok(())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.any.is_none()
+            && self.domains.is_empty()
+            && self.suffixes.is_empty()
+            && self.networks.is_empty()
+    }
+
+    fn matches(&self, target: &TargetAddr) -> bool {
+        let port = match target {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        };
+        if self.any.as_ref().map_or(false, |ports| allows(ports, port)) {
+            return true;
+        }
+        match target {
+            TargetAddr::Ip(addr) => self.matches_ip(addr.ip(), port),
+            TargetAddr::Domain(domain, _) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                if let Ok(ip) = domain.parse::<IpAddr>() {
+                    return self.matches_ip(ip, port);
+                }
+                if self
+                    .domains
+                    .get(&domain)
+                    .map_or(false, |ports| allows(ports, port))
+                {
+                    return true;
+                }
+                let mut name = domain.as_str();
+                loop {
+                    if self
+                        .suffixes
+                        .get(name)
+                        .map_or(false, |ports| allows(ports, port))
+                    {
+                        return true;
+                    }
+                    match name.find('.') {
+                        Some(pos) => name = &name[pos + 1..],
+                        None => return false,
+                    }
+                }
+            }
+        }
+    }
+
+    fn matches_ip(&self, ip: IpAddr, port: u16) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        self.networks
+            .iter()
+            .filter(|((is_v4, _), _)| *is_v4 == ip.is_ipv4())
+            .any(|((_, prefix_len), networks)| {
+                networks
+                    .get(&mask(ip, *prefix_len))
+                    .map_or(false, |ports| allows(ports, port))
+            })
+    }
+}
+
+fn allows(ports: &[(u16, u16)], port: u16) -> bool {
+    ports.is_empty() || ports.iter().any(|(lo, hi)| (*lo..=*hi).contains(&port))
+}
+
+fn insert<K: Eq + Hash>(map: &mut HashMap<K, Ports>, key: K, ports: Ports) {
+    match map.entry(key) {
+        Entry::Occupied(mut entry) => merge(entry.get_mut(), ports),
+        Entry::Vacant(entry) => {
+            entry.insert(ports);
+        }
+    }
+}
+
+fn merge(existing: &mut Ports, ports: Ports) {
+    // An empty list already matches every port.
+    if ports.is_empty() {
+        existing.clear();
+    } else if !existing.is_empty() {
+        existing.extend(ports);
+    }
+}
+
+fn split_ports(pattern: &str) -> Result<(&str, Option<(u16, u16)>)> {
+    let (host, ports) = if pattern.starts_with('[') {
+        let end = pattern
+            .find(']')
+            .ok_or(Error::InvalidPattern("unclosed bracket"))?;
+        let rest = &pattern[end + 1..];
+        let (prefix, ports) = match rest.rfind(':') {
+            Some(pos) => (&rest[..pos], Some(&rest[pos + 1..])),
+            None => (rest, None),
+        };
+        (&pattern[..end + 1 + prefix.len()], ports)
+    } else if pattern.matches(':').count() == 1 {
+        let pos = pattern.find(':').unwrap();
+        (&pattern[..pos], Some(&pattern[pos + 1..]))
+    } else {
+        (pattern, None)
+    };
+
+    let ports = match ports {
+        Some(ports) => {
+            let mut parts = ports.splitn(2, '-');
+            let lo = parse_port(parts.next())?;
+            let hi = match parts.next() {
+                Some(hi) => parse_port(Some(hi))?,
+                None => lo,
+            };
+            if lo > hi {
+                return Err(Error::InvalidPattern("invalid port range"));
+            }
+            Some((lo, hi))
+        }
+        None => None,
+    };
+    Ok((host, ports))
+}
+
+fn parse_port(port: Option<&str>) -> Result<u16> {
+    port.and_then(|port| port.parse().ok())
+        .ok_or(Error::InvalidPattern("invalid port"))
+}
+
+fn parse_network(host: &str) -> Result<Option<(IpAddr, u8)>> {
+    let (addr, prefix_len) = match host.find('/') {
+        Some(pos) => (&host[..pos], Some(&host[pos + 1..])),
+        None => (host, None),
+    };
+    let addr = addr.trim_start_matches('[').trim_end_matches(']');
+    let ip = match addr.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) if prefix_len.is_some() || host.starts_with('[') => {
+            return Err(Error::InvalidPattern("invalid IP address"))
+        }
+        Err(_) => return Ok(None),
+    };
+    let max_len = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix_len {
+        Some(len) => len
+            .parse()
+            .ok()
+            .filter(|len| *len <= max_len)
+            .ok_or(Error::InvalidPattern("invalid prefix length"))?,
+        None => max_len,
+    };
+    Ok(Some((ip, prefix_len)))
+}
+
+fn mask(ip: IpAddr, prefix_len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4)
+                .checked_shr(32 - u32::from(prefix_len))
+                .map_or(0, |bits| bits << (32 - u32::from(prefix_len)));
+            IpAddr::V4(bits.into())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6)
+                .checked_shr(128 - u32::from(prefix_len))
+                .map_or(0, |bits| bits << (128 - u32::from(prefix_len)));
+            IpAddr::V6(bits.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IntoTargetAddr;
+
+    fn matches(matcher: &TargetMatcher, target: &str) -> bool {
+        matcher.matches(&target.into_target_addr().unwrap())
+    }
+
+    #[test]
+    fn matches_exact_and_wildcard_domains() -> Result<()> {
+        let matcher = TargetMatcher::from_list("example.com, *.internal .corp.net")?;
+        assert!(matches(&matcher, "example.com:80"));
+        assert!(matches(&matcher, "EXAMPLE.com.:80"));
+        assert!(!matches(&matcher, "www.example.com:80"));
+        assert!(matches(&matcher, "db.prod.internal:5432"));
+        assert!(matches(&matcher, "internal:5432"));
+        assert!(matches(&matcher, "corp.net:443"));
+        assert!(matches(&matcher, "mail.corp.net:443"));
+        assert!(!matches(&matcher, "notcorp.net:443"));
+        Ok(())
+    }
+
+    #[test]
+    fn matches_ip_and_cidr() -> Result<()> {
+        let matcher = TargetMatcher::from_list("10.0.0.0/8,192.168.1.1,[fe80::]/10")?;
+        assert!(matches(&matcher, "10.20.30.40:80"));
+        assert!(!matches(&matcher, "11.0.0.1:80"));
+        assert!(matches(&matcher, "192.168.1.1:22"));
+        assert!(!matches(&matcher, "192.168.1.2:22"));
+        assert!(matches(&matcher, "[fe80::1]:80"));
+        assert!(!matches(&matcher, "[2001:db8::1]:80"));
+        assert!(matches(&matcher, "[::ffff:10.0.0.1]:80"));
+        Ok(())
+    }
+
+    #[test]
+    fn matches_port_ranges() -> Result<()> {
+        let matcher = TargetMatcher::from_list("example.com:443,10.0.0.0/8:8000-8999,[::1]:22")?;
+        assert!(matches(&matcher, "example.com:443"));
+        assert!(!matches(&matcher, "example.com:80"));
+        assert!(matches(&matcher, "10.0.0.1:8080"));
+        assert!(!matches(&matcher, "10.0.0.1:9000"));
+        assert!(matches(&matcher, "[::1]:22"));
+        assert!(!matches(&matcher, "[::1]:23"));
+        Ok(())
+    }
+
+    #[test]
+    fn matches_everything_with_star() -> Result<()> {
+        let matcher = TargetMatcher::from_list("*")?;
+        assert!(matches(&matcher, "example.com:443"));
+        assert!(matches(&matcher, "1.1.1.1:53"));
+        assert!(!TargetMatcher::new().matches(&"1.1.1.1:53".into_target_addr()?));
+        Ok(())
+    }
+
+    #[test]
+    fn deny_rules_take_precedence() -> Result<()> {
+        let matcher =
+            TargetMatcher::from_list("!admin.example.com, *.example.com, !10.0.0.0/8:22")?;
+        assert!(matches(&matcher, "www.example.com:443"));
+        assert!(!matches(&matcher, "admin.example.com:443"));
+
+        let mut matcher = TargetMatcher::from_list("*")?;
+        matcher.deny("10.0.0.0/8:22")?;
+        assert!(matches(&matcher, "10.0.0.1:80"));
+        assert!(!matches(&matcher, "10.0.0.1:22"));
+        // Deny rules alone match nothing.
+        let matcher = TargetMatcher::from_list("!example.com")?;
+        assert!(!matches(&matcher, "example.com:80"));
+        assert!(!matches(&matcher, "www.example.org:80"));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_patterns_should_fail() {
+        for pattern in &[
+            "10.0.0.0/33",
+            "example.com:99999",
+            "a*b.com",
+            "[::1",
+            ":80-70",
+            "!!example.com",
+        ] {
+            assert!(TargetMatcher::new().add(pattern).is_err(), "{}", pattern);
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Message-oriented transports, e.g. WebSocket connections, as byte streams.
+//!
+//! `connect_websocket` runs the SOCKS5 negotiation and the tunneled data over a WebSocket
+//! connection of `tokio-tungstenite`, for proxy servers exposed behind an HTTP endpoint.
+//! Other transports of binary messages can be wrapped in a `MessageStream` and passed to
+//! `Socks5Stream::connect_with_socket`.
+
+use crate::{
+    tcp::{validate_auth, SocketConnectFuture, Socks5Stream},
+    Authentication, Error, IntoTargetAddr, Result, TargetAddr,
+};
+use futures::{try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use std::io::{self, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{
+    tungstenite::{self, handshake::client::Request, Message},
+    ConnectAsync, WebSocketStream,
+};
+
+/// A byte stream over a `Stream` and `Sink` of binary messages.
+///
+/// It lets the SOCKS5 negotiation and the tunneled data run over a transport which carries
+/// messages rather than bytes, such as a WebSocket connection to a proxy server behind an
+/// HTTP endpoint, by passing it to `Socks5Stream::connect_with_socket`. Each write is sent
+/// as one message, and messages are read in order regardless of their boundaries.
+#[derive(Debug)]
+pub struct MessageStream<T> {
+    inner: T,
+    message: Vec<u8>,
+    pos: usize,
+}
+
+impl<T> MessageStream<T> {
+    /// Wraps a transport of binary messages.
+    pub fn new(inner: T) -> Self {
+        MessageStream {
+            inner,
+            message: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Consumes the `MessageStream`, returning the transport.
+    ///
+    /// The unread part of the last received message is lost.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+fn would_block() -> io::Error {
+    io::ErrorKind::WouldBlock.into()
+}
+
+impl<T, E> Read for MessageStream<T>
+where
+    T: Stream<Item = Vec<u8>, Error = E>,
+    E: Into<io::Error>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.message.len() {
+            match self.inner.poll().map_err(Into::into)? {
+                Async::Ready(Some(message)) => {
+                    self.message = message;
+                    self.pos = 0;
+                }
+                Async::Ready(None) => return Ok(0),
+                Async::NotReady => return Err(would_block()),
+            }
+        }
+        let n = (&self.message[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<T, E> Write for MessageStream<T>
+where
+    T: Sink<SinkItem = Vec<u8>, SinkError = E>,
+    E: Into<io::Error>,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.inner.start_send(buf.to_vec()).map_err(Into::into)? {
+            AsyncSink::Ready => {
+                // The negotiation does not flush, so start sending right away.
+                self.inner.poll_complete().map_err(Into::into)?;
+                Ok(buf.len())
+            }
+            AsyncSink::NotReady(_) => Err(would_block()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.poll_complete().map_err(Into::into)? {
+            Async::Ready(()) => Ok(()),
+            Async::NotReady => Err(would_block()),
+        }
+    }
+}
+
+impl<T, E> AsyncRead for MessageStream<T>
+where
+    T: Stream<Item = Vec<u8>, Error = E>,
+    E: Into<io::Error>,
+{
+}
+
+impl<T, E> AsyncWrite for MessageStream<T>
+where
+    T: Sink<SinkItem = Vec<u8>, SinkError = E>,
+    E: Into<io::Error>,
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.close().map_err(Into::into)
+    }
+}
+
+/// Runs the WebSocket handshake of `request`, e.g. a `url::Url` such as
+/// `ws://proxy.example.com/socks`, on `stream`, then connects to a target server through a
+/// SOCKS5 proxy, with the negotiation and the tunneled data in binary messages.
+///
+/// `stream` is the connection to the HTTP endpoint, e.g. a `TcpStream` or, for `wss://`, a
+/// TLS stream on it.
+///
+/// # Error
+///
+/// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+pub fn connect_websocket<'r, 't, S, R, T>(
+    stream: S,
+    request: R,
+    target: T,
+) -> Result<WebSocketConnectFuture<'static, 't, S>>
+where
+    S: AsyncRead + AsyncWrite,
+    R: Into<Request<'r>>,
+    T: IntoTargetAddr<'t>,
+{
+    connect_websocket_raw(stream, request, target, Authentication::None)
+}
+
+/// Connects to a target server through a SOCKS5 proxy using given username and password,
+/// over a WebSocket connection like `connect_websocket`.
+///
+/// # Error
+///
+/// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+/// fails if the length of `username` or `password` is not between 1 and 255.
+pub fn connect_websocket_with_password<'a, 'r, 't, S, R, T>(
+    stream: S,
+    request: R,
+    target: T,
+    username: &'a str,
+    password: &'a str,
+) -> Result<WebSocketConnectFuture<'a, 't, S>>
+where
+    S: AsyncRead + AsyncWrite,
+    R: Into<Request<'r>>,
+    T: IntoTargetAddr<'t>,
+{
+    let auth = Authentication::Password {
+        username: username.as_bytes().into(),
+        password: password.as_bytes().into(),
+    };
+    connect_websocket_raw(stream, request, target, auth)
+}
+
+fn connect_websocket_raw<'a, 'r, 't, S, R, T>(
+    stream: S,
+    request: R,
+    target: T,
+    auth: Authentication<'a, MessageStream<BinaryMessages<S>>>,
+) -> Result<WebSocketConnectFuture<'a, 't, S>>
+where
+    S: AsyncRead + AsyncWrite,
+    R: Into<Request<'r>>,
+    T: IntoTargetAddr<'t>,
+{
+    validate_auth(&auth)?;
+    let target = target.into_target_addr()?;
+    Ok(WebSocketConnectFuture {
+        state: WebSocketState::Handshaking(
+            tokio_tungstenite::client_async(request, stream),
+            Some((auth, target)),
+        ),
+    })
+}
+
+/// A `Future` which resolves to a socket to the target server through a proxy, over a
+/// WebSocket connection on `S`.
+///
+/// This `struct` is created by `connect_websocket` and its variants.
+pub struct WebSocketConnectFuture<'a, 't, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    state: WebSocketState<'a, 't, S>,
+}
+
+enum WebSocketState<'a, 't, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    Handshaking(
+        ConnectAsync<S>,
+        Option<(
+            Authentication<'a, MessageStream<BinaryMessages<S>>>,
+            TargetAddr<'t>,
+        )>,
+    ),
+    Negotiating(Box<SocketConnectFuture<'a, 't, MessageStream<BinaryMessages<S>>>>),
+}
+
+impl<'a, 't, S> Future for WebSocketConnectFuture<'a, 't, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type Item = Socks5Stream<MessageStream<BinaryMessages<S>>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Error> {
+        loop {
+            self.state = match &mut self.state {
+                WebSocketState::Handshaking(handshake, request) => {
+                    let (ws, _) = try_ready!(handshake.poll().map_err(ws_error));
+                    let (auth, target) = request.take().unwrap();
+                    let stream = MessageStream::new(BinaryMessages(ws));
+                    let negotiation = SocketConnectFuture::new(stream, auth, target);
+                    WebSocketState::Negotiating(Box::new(negotiation))
+                }
+                WebSocketState::Negotiating(negotiation) => return negotiation.poll(),
+            }
+        }
+    }
+}
+
+/// The binary messages of a WebSocket connection as a `Stream` and `Sink` of payloads.
+///
+/// Text messages are dropped, and control messages are answered by `tokio-tungstenite`.
+pub struct BinaryMessages<S>(WebSocketStream<S>);
+
+impl<S> BinaryMessages<S> {
+    /// Consumes the `BinaryMessages`, returning the WebSocket connection.
+    pub fn into_inner(self) -> WebSocketStream<S> {
+        self.0
+    }
+}
+
+impl<S> Stream for BinaryMessages<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        loop {
+            match try_ready!(self.0.poll().map_err(ws_io_error)) {
+                Some(Message::Binary(data)) => return Ok(Async::Ready(Some(data))),
+                Some(_) => {}
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+impl<S> Sink for BinaryMessages<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type SinkItem = Vec<u8>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, data: Vec<u8>) -> StartSend<Vec<u8>, io::Error> {
+        match self
+            .0
+            .start_send(Message::binary(data))
+            .map_err(ws_io_error)?
+        {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(message) => Ok(AsyncSink::NotReady(message.into_data())),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        self.0.poll_complete().map_err(ws_io_error)
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        self.0.close().map_err(ws_io_error)
+    }
+}
+
+/// Converts an error of the WebSocket connection, keeping IO errors as they are.
+// `io::Error::other`, which the lint suggests, needs a newer compiler than the crate supports.
+#[allow(clippy::io_other_error)]
+fn ws_io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+fn ws_error(e: tungstenite::Error) -> Error {
+    ws_io_error(e).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tcp::Socks5Stream, Result, TargetAddr};
+    use futures::{
+        sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Future, StartSend,
+    };
+
+    /// One end of an in-memory transport of messages.
+    struct Pipe {
+        tx: UnboundedSender<Vec<u8>>,
+        rx: UnboundedReceiver<Vec<u8>>,
+    }
+
+    impl Stream for Pipe {
+        type Item = Vec<u8>;
+        type Error = io::Error;
+
+        fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+            Ok(self.rx.poll().unwrap())
+        }
+    }
+
+    impl Sink for Pipe {
+        type SinkItem = Vec<u8>;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, item: Vec<u8>) -> StartSend<Vec<u8>, io::Error> {
+            self.tx
+                .start_send(item)
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            self.tx
+                .poll_complete()
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))
+        }
+    }
+
+    #[test]
+    fn negotiates_over_messages() -> Result<()> {
+        let (client_tx, server_rx) = mpsc::unbounded();
+        let (server_tx, client_rx) = mpsc::unbounded();
+        // The reply is split across messages, and the greeting reply shares one with it.
+        server_tx
+            .unbounded_send(b"\x05\x00\x05\x00".to_vec())
+            .unwrap();
+        server_tx
+            .unbounded_send(b"\x00\x01\x7f\0\0\x01\x04\x38".to_vec())
+            .unwrap();
+        let pipe = Pipe {
+            tx: client_tx,
+            rx: client_rx,
+        };
+        let conn = Socks5Stream::connect_with_socket(MessageStream::new(pipe), "example.com:443")?;
+        let stream = conn.wait()?;
+        assert_eq!(
+            stream.target_addr(),
+            TargetAddr::Ip(([127, 0, 0, 1], 1080).into())
+        );
+        drop(stream);
+        let sent = server_rx.collect().wait().unwrap();
+        assert_eq!(
+            sent,
+            vec![
+                b"\x05\x01\x00".to_vec(),
+                b"\x05\x01\x00\x03\x0bexample.com\x01\xbb".to_vec(),
+            ]
+        );
+        Ok(())
+    }
+
+    /// Receives a message on the WebSocket connection and answers it with `reply`.
+    fn exchange<S>(
+        ws: WebSocketStream<S>,
+        reply: &'static [u8],
+    ) -> impl Future<Item = (WebSocketStream<S>, Vec<u8>), Error = Error>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        ws.into_future()
+            .map_err(|(e, _)| ws_error(e))
+            .and_then(move |(message, ws)| {
+                ws.send(Message::binary(reply))
+                    .map(|ws| (ws, message.unwrap().into_data()))
+                    .map_err(ws_error)
+            })
+    }
+
+    #[test]
+    fn negotiates_over_websocket() -> Result<()> {
+        use tokio_tcp::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())?;
+        let addr = listener.local_addr()?;
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| Error::from(e))
+            .and_then(|(tcp, _)| tokio_tungstenite::accept_async(tcp.unwrap()).map_err(ws_error))
+            .and_then(|ws| exchange(ws, b"\x05\x00"))
+            .and_then(|(ws, greeting)| {
+                exchange(ws, b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38")
+                    .map(|(ws, request)| (ws, greeting, request))
+            });
+        let url = url::Url::parse(&format!("ws://{}/socks", addr)).unwrap();
+        let client = TcpStream::connect(&addr)
+            .map_err(Error::from)
+            .and_then(|tcp| connect_websocket(tcp, url, "example.com:443"))
+            .flatten();
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+        let (stream, (_ws, greeting, request)) = runtime.block_on(client.join(server))?;
+        assert_eq!(greeting, b"\x05\x01\x00");
+        assert_eq!(request, b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+        assert_eq!(
+            stream.target_addr(),
+            TargetAddr::Ip(([127, 0, 0, 1], 1080).into())
+        );
+        Ok(())
+    }
+}
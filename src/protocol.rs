@@ -0,0 +1,261 @@
+//! Simulation of the SOCKS5 negotiation against a scripted proxy server.
+//!
+//! It runs the client side of `Socks5Stream::connect_with_socket` on an in-memory
+//! connection, so the exact bytes a misbehaving proxy server sends can be turned into a
+//! failing test without any network. The expectations of `Outcome`, e.g.
+//! `expect_connect_to`, turn what the client sent into assertions.
+
+use crate::{
+    tcp::{HandshakeInfo, Socks5Stream},
+    IntoTargetAddr, Result, TargetAddr,
+};
+use futures::{Future, Poll};
+use std::io::{self, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The settings of the simulated client.
+#[derive(Debug)]
+pub struct ClientConfig {
+    target: TargetAddr<'static>,
+    credentials: Option<(String, String)>,
+}
+
+impl ClientConfig {
+    /// Creates a client which connects to `target` without authentication.
+    pub fn new(target: TargetAddr<'static>) -> Self {
+        ClientConfig {
+            target,
+            credentials: None,
+        }
+    }
+
+    /// Makes the client offer the username/password authentication with given username and
+    /// password.
+    pub fn password<U, P>(mut self, username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+}
+
+/// The outcome of a simulated negotiation.
+#[derive(Debug)]
+pub struct Outcome {
+    /// The result of the negotiation.
+    pub result: Result<HandshakeInfo>,
+    /// All bytes sent by the client.
+    pub sent: Vec<u8>,
+    /// The bytes of the server script which the client did not read.
+    pub unread: Vec<u8>,
+}
+
+impl Outcome {
+    /// Panics unless the client offered exactly `methods`, in the given order, e.g.
+    /// `&[0x00, 0x02]` for no authentication and username/password.
+    pub fn expect_methods(&self, methods: &[u8]) -> &Self {
+        let sent = Sent::parse(&self.sent);
+        assert_eq!(sent.methods, Some(methods), "unexpected methods offered");
+        self
+    }
+
+    /// Panics unless the client authenticated with `username` and `password`.
+    pub fn expect_auth(&self, username: &str, password: &str) -> &Self {
+        let sent = Sent::parse(&self.sent);
+        assert_eq!(
+            sent.auth,
+            Some((username.as_bytes(), password.as_bytes())),
+            "unexpected credentials sent"
+        );
+        self
+    }
+
+    /// Panics unless the client sent a CONNECT request to `target`.
+    pub fn expect_connect_to<'t, T>(&self, target: T) -> &Self
+    where
+        T: IntoTargetAddr<'t>,
+    {
+        let target = target.into_target_addr().expect("invalid target");
+        let mut request = [0; 3 + TargetAddr::MAX_ENCODED_LEN];
+        request[..3].copy_from_slice(&[0x05, 0x01, 0x00]);
+        let len = 3 + target.encode(&mut request[3..]);
+        let sent = Sent::parse(&self.sent);
+        assert_eq!(
+            sent.request,
+            &request[..len],
+            "unexpected request, expected CONNECT to {:?}",
+            target
+        );
+        self
+    }
+}
+
+/// The messages in the bytes sent by the client, as far as they could be told apart.
+struct Sent<'a> {
+    methods: Option<&'a [u8]>,
+    auth: Option<(&'a [u8], &'a [u8])>,
+    request: &'a [u8],
+}
+
+impl<'a> Sent<'a> {
+    fn parse(sent: &'a [u8]) -> Self {
+        let mut parsed = Sent {
+            methods: None,
+            auth: None,
+            request: &[],
+        };
+        let mut rest = sent;
+        if rest.len() < 2 || rest[0] != 0x05 || rest.len() < 2 + rest[1] as usize {
+            return parsed;
+        }
+        let (methods, after) = rest[2..].split_at(rest[1] as usize);
+        parsed.methods = Some(methods);
+        rest = after;
+        // The username/password sub-negotiation has version 1, the request version 5.
+        if rest.first() == Some(&0x01) {
+            let ulen = *rest.get(1).unwrap_or(&0) as usize;
+            let plen = match rest.get(2 + ulen) {
+                Some(&plen) if rest.len() >= 3 + ulen + plen as usize => plen as usize,
+                _ => return parsed,
+            };
+            parsed.auth = Some((&rest[2..2 + ulen], &rest[3 + ulen..3 + ulen + plen]));
+            rest = &rest[3 + ulen + plen..];
+        }
+        parsed.request = rest;
+        parsed
+    }
+}
+
+/// Runs the negotiation of `client` against a proxy server which sends `server_script`,
+/// regardless of what the client sends.
+///
+/// The client reads the script as if it arrived in a single segment, and sees the end of
+/// the connection when the script is exhausted.
+pub fn simulate(client: &ClientConfig, server_script: &[u8]) -> Outcome {
+    let mut input = server_script;
+    let mut sent = Vec::new();
+    let result = {
+        let script = Script {
+            input: &mut input,
+            sent: &mut sent,
+        };
+        let target = client.target.to_owned();
+        let conn = match &client.credentials {
+            Some((username, password)) => {
+                Socks5Stream::connect_with_socket_and_password(script, target, username, password)
+            }
+            None => Socks5Stream::connect_with_socket(script, target),
+        };
+        // The script never blocks, so waiting does not either.
+        conn.and_then(|conn| conn.wait())
+            .map(|stream| stream.into_parts().1)
+    };
+    Outcome {
+        result,
+        sent,
+        unread: input.to_vec(),
+    }
+}
+
+/// An in-memory connection to the scripted proxy server.
+struct Script<'a, 'b> {
+    input: &'a mut &'b [u8],
+    sent: &'a mut Vec<u8>,
+}
+
+impl<'a, 'b> Read for Script<'a, 'b> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl<'a, 'b> Write for Script<'a, 'b> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, 'b> AsyncRead for Script<'a, 'b> {}
+
+impl<'a, 'b> AsyncWrite for Script<'a, 'b> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, IntoTargetAddr};
+
+    fn client() -> ClientConfig {
+        ClientConfig::new("example.com:443".into_target_addr().unwrap())
+    }
+
+    #[test]
+    fn simulates_successful_negotiation() -> Result<()> {
+        let client = client().password("mylogin", "mypassword");
+        let outcome = simulate(
+            &client,
+            b"\x05\x02\x01\x00\x05\x00\x00\x01\x7f\0\0\x01\x04\x38extra",
+        );
+        let info = outcome.result?;
+        assert_eq!(info.target, TargetAddr::Ip(([127, 0, 0, 1], 1080).into()));
+        assert_eq!(
+            &outcome.sent[..],
+            &b"\x05\x02\x00\x02\x01\x07mylogin\x0amypassword\
+               \x05\x01\x00\x03\x0bexample.com\x01\xbb"[..]
+        );
+        assert_eq!(&outcome.unread[..], b"extra");
+        Ok(())
+    }
+
+    #[test]
+    fn expectations_check_what_the_client_sent() {
+        let with_password = client().password("mylogin", "mypassword");
+        simulate(
+            &with_password,
+            b"\x05\x02\x01\x00\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+        )
+        .expect_methods(&[0x00, 0x02])
+        .expect_auth("mylogin", "mypassword")
+        .expect_connect_to("example.com:443");
+        simulate(&client(), b"\x05\x00\x05\x00\x00\x01\x7f\0\0\x01\x04\x38")
+            .expect_methods(&[0x00])
+            .expect_connect_to("example.com:443");
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected request")]
+    fn unexpected_target_fails() {
+        simulate(&client(), b"\x05\x00\x05\x00\x00\x01\x7f\0\0\x01\x04\x38")
+            .expect_connect_to("example.org:443");
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected credentials")]
+    fn unexpected_credentials_fail() {
+        let client = client().password("mylogin", "other");
+        simulate(
+            &client,
+            b"\x05\x02\x01\x00\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+        )
+        .expect_auth("mylogin", "mypassword");
+    }
+
+    #[test]
+    fn truncated_script_ends_the_connection() {
+        let outcome = simulate(&client(), b"\x05\x00\x05\x00");
+        match outcome.result {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert!(outcome.unread.is_empty());
+    }
+}
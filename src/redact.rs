@@ -0,0 +1,98 @@
+//! Redaction of addresses in the log records of the crate.
+//!
+//! By default the log records of the crate name the proxy servers and targets they are
+//! about. Applications subject to data-minimization requirements can hide the hostnames and
+//! IP addresses with `set_enabled(true)`. Ports are kept, since they tell the services apart
+//! without identifying anyone.
+
+use crate::TargetAddr;
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether hostnames and IP addresses are replaced by `<redacted>` in the log records
+/// of the crate. It applies to every connection, including those already in progress.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns whether addresses are redacted in the log records of the crate.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// An address to be displayed in a log record, with the host hidden if redaction is
+/// enabled. `None` stands for an address which is not known.
+pub(crate) struct LogAddr<'a> {
+    addr: Option<TargetAddr<'a>>,
+    redact: bool,
+}
+
+impl<'a> LogAddr<'a> {
+    fn new(addr: Option<TargetAddr<'a>>) -> Self {
+        LogAddr {
+            addr,
+            redact: is_enabled(),
+        }
+    }
+}
+
+impl From<SocketAddr> for LogAddr<'static> {
+    fn from(addr: SocketAddr) -> Self {
+        LogAddr::new(Some(TargetAddr::Ip(addr)))
+    }
+}
+
+impl From<Option<SocketAddr>> for LogAddr<'static> {
+    fn from(addr: Option<SocketAddr>) -> Self {
+        LogAddr::new(addr.map(TargetAddr::Ip))
+    }
+}
+
+impl<'a> From<&'a TargetAddr<'_>> for LogAddr<'a> {
+    fn from(addr: &'a TargetAddr<'_>) -> Self {
+        LogAddr::new(Some(match addr {
+            TargetAddr::Ip(addr) => TargetAddr::Ip(*addr),
+            TargetAddr::Domain(domain, port) => TargetAddr::Domain((&**domain).into(), *port),
+        }))
+    }
+}
+
+impl fmt::Display for LogAddr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.addr {
+            None => f.write_str("unknown address"),
+            Some(addr) if self.redact => write!(f, "<redacted>:{}", addr.port()),
+            Some(TargetAddr::Ip(addr)) => write!(f, "{}", addr),
+            Some(TargetAddr::Domain(domain, port)) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IntoTargetAddr;
+
+    fn display(addr: TargetAddr<'_>, redact: bool) -> String {
+        LogAddr {
+            addr: Some(addr),
+            redact,
+        }
+        .to_string()
+    }
+
+    #[test]
+    fn hosts_are_hidden_when_redacting() {
+        let domain = "www.example.com:443".into_target_addr().unwrap();
+        let ip = "[2001:db8::1]:80".into_target_addr().unwrap();
+        assert_eq!(display(domain.to_owned(), false), "www.example.com:443");
+        assert_eq!(display(ip.to_owned(), false), "[2001:db8::1]:80");
+        assert_eq!(display(domain, true), "<redacted>:443");
+        assert_eq!(display(ip, true), "<redacted>:80");
+    }
+}
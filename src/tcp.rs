@@ -1,29 +1,177 @@
-use crate::{Authentication, Error, IntoTargetAddr, Result, TargetAddr, ToProxyAddrs};
-use bytes::{Buf, BufMut};
-use derefable::Derefable;
-use futures::{stream, try_ready, Async, Future, Poll, Stream};
+#[cfg(feature = "log")]
+use crate::redact::LogAddr;
+use crate::{
+    auth::{AuthFuture, AuthMethod, Method},
+    layer::Layer,
+    Authentication, DetectedProtocol, Error, IntoTargetAddr, Result, TargetAddr, ToProxyAddrs,
+};
+use bytes::{Buf, BufMut, IntoBuf};
+use futures::{
+    future::Map,
+    stream::{Once, SplitSink, SplitStream},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    try_ready, Async, Future, Poll, Stream,
+};
+use socket2::SockRef;
 use std::borrow::Borrow;
 use std::io::{self, Read, Write};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_codec::{Decoder, Encoder, Framed};
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_tcp::{ConnectFuture as TokioConnect, TcpStream};
+use tokio_timer::Delay;
+#[cfg(all(unix, feature = "unix"))]
+use tokio_uds::UnixStream;
 
 #[repr(u8)]
-#[derive(Clone, Copy)]
-enum Command {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Command {
     Connect = 0x01,
     Bind = 0x02,
+    #[cfg(feature = "udp")]
     Associate = 0x03,
+    #[cfg(feature = "tor")]
+    TorResolve = 0xf0,
+    #[cfg(feature = "tor")]
+    TorResolvePtr = 0xf1,
 }
 
+/// Information about a finished SOCKS5 negotiation.
+#[derive(Debug)]
+pub struct HandshakeInfo {
+    /// The address of the proxy server.
+    pub proxy_addr: SocketAddr,
+    /// The target address sent in the request.
+    pub requested_target: TargetAddr<'static>,
+    /// The address returned in the reply of the proxy server.
+    pub target: TargetAddr<'static>,
+    /// The authentication method selected by the proxy server.
+    pub auth: NegotiatedAuth,
+}
+
+impl HandshakeInfo {
+    /// Returns the address returned in the reply, classified by how it relates to the
+    /// request.
+    pub fn bnd_addr(&self) -> BndAddr<'_> {
+        match &self.target {
+            TargetAddr::Ip(addr) if addr.ip().is_unspecified() && addr.port() == 0 => {
+                BndAddr::Unspecified
+            }
+            target if *target == self.requested_target => BndAddr::AsRequested(target),
+            target => BndAddr::ProxyChosen(target),
+        }
+    }
+}
+
+/// The address returned in the reply of the proxy server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BndAddr<'a> {
+    /// The proxy server did not report an address, i.e. it replied with `0.0.0.0:0`.
+    Unspecified,
+    /// The proxy server replied with the requested target.
+    AsRequested(&'a TargetAddr<'static>),
+    /// The proxy server chose the address, e.g. the local address of its connection to the
+    /// target, the listener of a BIND request or the relay of a UDP association.
+    ProxyChosen(&'a TargetAddr<'static>),
+}
+
+/// The authentication method selected by the proxy server during the method selection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NegotiatedAuth {
+    /// No authentication was required.
+    None,
+    /// The username/password authentication of RFC 1929.
+    Password,
+    /// A custom method, identified by its method ID.
+    Custom(u8),
+}
+
+impl NegotiatedAuth {
+    pub(crate) fn from_method(method: u8) -> Self {
+        match method {
+            0x00 => NegotiatedAuth::None,
+            0x02 => NegotiatedAuth::Password,
+            m => NegotiatedAuth::Custom(m),
+        }
+    }
+}
+
+/// A step of the negotiation with the proxy server, reported by the `progress` method of
+/// the connect futures, e.g. `ConnectFuture::progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectProgress {
+    /// Waiting for the next proxy address.
+    Resolving,
+    /// Opening a TCP connection to the proxy server.
+    Connecting(SocketAddr),
+    /// Negotiating the authentication method.
+    Negotiating,
+    /// Performing the sub-negotiation of the selected authentication method.
+    Authenticating,
+    /// Sending the request and waiting for the reply.
+    Requesting,
+    /// The proxy server has accepted the request.
+    Established,
+}
+
+/// Sends each step to the receiver returned by `progress`, if any.
+struct ProgressSender(Option<(UnboundedSender<ConnectProgress>, Option<ConnectProgress>)>);
+
+impl ProgressSender {
+    fn report(&mut self, step: ConnectProgress) {
+        if let Some((tx, last)) = &mut self.0 {
+            if *last != Some(step) {
+                *last = Some(step);
+                // The receiver may have been dropped, which only means nobody is watching.
+                let _ = tx.unbounded_send(step);
+            }
+        }
+    }
+}
+
+/// A class of traffic, which decides the DSCP value of packets sent to the proxy server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// Best effort traffic (CS0).
+    BestEffort,
+    /// Latency sensitive traffic, e.g. SSH or RDP sessions (AF21).
+    Interactive,
+    /// Bulk transfers which should yield to other traffic (CS1).
+    Bulk,
+}
+
+impl TrafficClass {
+    fn dscp(self) -> u8 {
+        match self {
+            TrafficClass::BestEffort => 0,
+            TrafficClass::Interactive => 18,
+            TrafficClass::Bulk => 8,
+        }
+    }
+}
+
+/// The `Sink` and `Stream` halves returned by `Socks5Stream::into_sink_stream`.
+pub type FramedHalves<C> = (
+    SplitSink<Framed<Socks5Stream, C>>,
+    SplitStream<Framed<Socks5Stream, C>>,
+);
+
 /// A SOCKS5 client.
 ///
-/// For convenience, it can be dereferenced to `tokio_tcp::TcpStream`.
-#[derive(Debug, Derefable)]
-pub struct Socks5Stream {
-    #[deref(mutable)]
-    tcp: TcpStream,
-    target: TargetAddr<'static>,
+/// For convenience, it can be dereferenced to `tokio_tcp::TcpStream`, so all of its socket
+/// options, such as `nodelay`, `ttl`, `linger` and their setters, can be called directly on
+/// the `Socks5Stream`.
+///
+/// The underlying socket is a `TcpStream` unless the negotiation was run on an established
+/// connection with `connect_with_socket`, in which case it can be any `AsyncRead +
+/// AsyncWrite`, e.g. a TLS stream or another `Socks5Stream`.
+#[derive(Debug)]
+pub struct Socks5Stream<S = TcpStream> {
+    tcp: S,
+    info: HandshakeInfo,
 }
 
 impl Socks5Stream {
@@ -42,6 +190,10 @@ impl Socks5Stream {
 
     /// Connects to a target server through a SOCKS5 proxy using given username and password.
     ///
+    /// Both "no authentication" and username/password are offered to the proxy server, so a
+    /// server which does not require authentication from this client can skip the
+    /// username/password sub-negotiation.
+    ///
     /// # Error
     ///
     /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
@@ -55,15 +207,165 @@ impl Socks5Stream {
         P: ToProxyAddrs,
         T: IntoTargetAddr<'t>,
     {
+        Self::connect_with_password_bytes(proxy, target, username.as_bytes(), password.as_bytes())
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using given username and password
+    /// as raw bytes.
+    ///
+    /// RFC 1929 allows arbitrary octets in credentials, so they are not required to be UTF-8.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_password_bytes<'a, 't, P, T>(
+        proxy: P,
+        target: T,
+        username: &'a [u8],
+        password: &'a [u8],
+    ) -> Result<ConnectFuture<'a, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Self::connect_raw(
+            proxy,
+            target,
+            Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
+            Command::Connect,
+        )
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using a custom authentication
+    /// method.
+    ///
+    /// Only the ID of `method` is offered to the proxy server.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_auth<'t, P, T, A>(
+        proxy: P,
+        target: T,
+        method: A,
+    ) -> Result<ConnectFuture<'static, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+        A: AuthMethod + 'static,
+    {
+        Self::connect_raw(
+            proxy,
+            target,
+            Authentication::Custom(Arc::new(method)),
+            Command::Connect,
+        )
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy, offering several authentication
+    /// methods in the order of preference.
+    ///
+    /// The proxy server selects one of `methods`, and the sub-negotiation of the selected
+    /// method is performed, e.g. no password is sent if the proxy server selects
+    /// `Method::None`.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`. It
+    /// fails if `methods` is empty, offers a method twice or contains an invalid username or
+    /// password.
+    pub fn connect_with_methods<'a, 't, P, T, M>(
+        proxy: P,
+        target: T,
+        methods: M,
+    ) -> Result<ConnectFuture<'a, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+        M: IntoIterator<Item = Method<'a>>,
+    {
+        let methods = methods.into_iter().map(Authentication::from).collect();
         Self::connect_raw(
             proxy,
             target,
-            Authentication::Password { username, password },
+            Authentication::Multiple(methods),
             Command::Connect,
         )
     }
 
-    fn connect_raw<'a, 't, P, T>(
+    /// Connects to a target server through a SOCKS5 proxy, wrapping the connection to the
+    /// proxy server with `layer` before the negotiation.
+    ///
+    /// The proxy addresses are tried in order until a connection is established, and
+    /// everything sent to the proxy server, including the negotiation, goes through the
+    /// layer.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_layered<'t, P, T, L>(
+        proxy: P,
+        target: T,
+        layer: L,
+    ) -> Result<LayeredConnectFuture<'static, 't, P::Output, L>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+        L: Layer,
+    {
+        Self::connect_layered_raw(proxy, target, layer, Authentication::None)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using given username and password,
+    /// wrapping the connection to the proxy server with `layer` before the negotiation.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+    /// fails if the length of `username` or `password` is not between 1 and 255.
+    pub fn connect_layered_with_password<'a, 't, P, T, L>(
+        proxy: P,
+        target: T,
+        layer: L,
+        username: &'a str,
+        password: &'a str,
+    ) -> Result<LayeredConnectFuture<'a, 't, P::Output, L>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+        L: Layer,
+    {
+        let auth = Authentication::Password {
+            username: username.as_bytes().into(),
+            password: password.as_bytes().into(),
+        };
+        Self::connect_layered_raw(proxy, target, layer, auth)
+    }
+
+    fn connect_layered_raw<'a, 't, P, T, L>(
+        proxy: P,
+        target: T,
+        layer: L,
+        auth: Authentication<'a, L::Output>,
+    ) -> Result<LayeredConnectFuture<'a, 't, P::Output, L>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+        L: Layer,
+    {
+        validate_auth(&auth)?;
+        Ok(LayeredConnectFuture {
+            proxy: proxy.to_proxy_addrs(),
+            layer,
+            conn: None,
+            negotiation: Negotiation::new(auth, Command::Connect, target.into_target_addr()?),
+        })
+    }
+
+    pub(crate) fn connect_raw<'a, 't, P, T>(
         proxy: P,
         target: T,
         auth: Authentication<'a>,
@@ -73,20 +375,7 @@ impl Socks5Stream {
         P: ToProxyAddrs,
         T: IntoTargetAddr<'t>,
     {
-        if let Authentication::Password { username, password } = auth {
-            let username_len = username.as_bytes().len();
-            if username_len < 1 || username_len > 255 {
-                Err(Error::InvalidAuthValues(
-                    "username length should between 1 to 255",
-                ))?
-            }
-            let password_len = password.as_bytes().len();
-            if password_len < 1 || password_len > 255 {
-                Err(Error::InvalidAuthValues(
-                    "password length should between 1 to 255",
-                ))?
-            }
-        }
+        validate_auth(&auth)?;
         Ok(ConnectFuture::new(
             auth,
             command,
@@ -95,14 +384,161 @@ impl Socks5Stream {
         ))
     }
 
-    /// Consumes the `Socks5Stream`, returning the inner `tokio_tcp::TcpStream`.
-    pub fn into_inner(self) -> TcpStream {
+    /// Resolves `domain` to an IP address through a Tor proxy, using Tor's RESOLVE
+    /// extension.
+    ///
+    /// The lookup is done by the Tor exit, so no DNS query leaks from the local host.
+    ///
+    /// # Error
+    ///
+    /// It fails if `domain` is overlong.
+    #[cfg(feature = "tor")]
+    pub fn tor_resolve<'t, P>(proxy: P, domain: &'t str) -> Result<TorResolveFuture<'t, P::Output>>
+    where
+        P: ToProxyAddrs,
+    {
+        let conn = Self::connect_raw(
+            proxy,
+            (domain, 0),
+            Authentication::None,
+            Command::TorResolve,
+        )?;
+        Ok(conn.and_then(resolved_ip as fn(_) -> _))
+    }
+
+    /// Resolves `ip` to a hostname through a Tor proxy, using Tor's RESOLVE_PTR extension.
+    ///
+    /// The reverse lookup is done by the Tor exit, so no DNS query leaks from the local
+    /// host.
+    #[cfg(feature = "tor")]
+    pub fn tor_resolve_ptr<P>(proxy: P, ip: std::net::IpAddr) -> TorResolvePtrFuture<P::Output>
+    where
+        P: ToProxyAddrs,
+    {
+        let conn = ConnectFuture::new(
+            Authentication::None,
+            Command::TorResolvePtr,
+            proxy.to_proxy_addrs(),
+            TargetAddr::Ip(SocketAddr::new(ip, 0)),
+        );
+        conn.and_then(resolved_hostname as fn(_) -> _)
+    }
+
+    /// Consumes the `Socks5Stream`, returning a `Future` which closes the connection politely.
+    ///
+    /// It flushes the written data, shuts down the write half and reads until the other end
+    /// closes the connection, discarding at most 64 KiB for at most 5 seconds, before
+    /// dropping the socket. Some stateful protocols lose the final bytes through a proxy if
+    /// the connection is dropped while data is still in flight.
+    pub fn close(self) -> Close {
+        Close {
+            stream: Some(self),
+            state: CloseState::Flush,
+            drained: 0,
+            drain_timeout: CLOSE_DRAIN_TIMEOUT,
+            deadline: None,
+        }
+    }
+
+    /// Consumes the `Socks5Stream`, returning a `Sink` and a `Stream` of frames encoded and
+    /// decoded with `codec`.
+    ///
+    /// Both halves share the same underlying socket. Frames sent to the `Sink` are flushed
+    /// when the `Sink` is polled for completion.
+    pub fn into_sink_stream<C>(self, codec: C) -> FramedHalves<C>
+    where
+        C: Decoder + Encoder,
+    {
+        Framed::new(self, codec).split()
+    }
+
+    /// Sets the traffic class of the connection to the proxy server.
+    ///
+    /// It sets the `IP_TOS` socket option, so it only takes effect on IPv4 connections.
+    #[cfg(not(any(
+        target_os = "fuchsia",
+        target_os = "redox",
+        target_os = "solaris",
+        target_os = "illumos",
+    )))]
+    pub fn set_traffic_class(&self, class: TrafficClass) -> io::Result<()> {
+        SockRef::from(&self.tcp).set_tos(u32::from(class.dscp()) << 2)
+    }
+
+    /// Builds a fresh tunnel to the same target through the same proxy server.
+    ///
+    /// Credentials are not stored in the stream, so the new connection performs no
+    /// authentication. Use `reconnect_with_password` if the proxy server requires it.
+    pub fn reconnect(&self) -> Result<ConnectFuture<'static, 'static, Once<SocketAddr, Error>>> {
+        Self::connect(self.info.proxy_addr, self.info.requested_target.to_owned())
+    }
+
+    /// Builds a fresh tunnel to the same target through the same proxy server using given
+    /// username and password.
+    ///
+    /// # Error
+    ///
+    /// It fails if the length of `username` or `password` is not between 1 and 255.
+    pub fn reconnect_with_password<'a>(
+        &self,
+        username: &'a str,
+        password: &'a str,
+    ) -> Result<ConnectFuture<'a, 'static, Once<SocketAddr, Error>>> {
+        Self::connect_with_password(
+            self.info.proxy_addr,
+            self.info.requested_target.to_owned(),
+            username,
+            password,
+        )
+    }
+}
+
+// `derefable` cannot derive these for a generic struct.
+impl<S> Deref for Socks5Stream<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.tcp
+    }
+}
+
+impl<S> DerefMut for Socks5Stream<S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.tcp
+    }
+}
+
+impl<S> Socks5Stream<S> {
+    /// Creates a `Socks5Stream` from a socket which has already finished the SOCKS5
+    /// negotiation and the information about the negotiation.
+    pub fn from_parts(tcp: S, info: HandshakeInfo) -> Self {
+        Socks5Stream { tcp, info }
+    }
+
+    /// Consumes the `Socks5Stream`, returning the inner socket and the information about
+    /// the negotiation.
+    pub fn into_parts(self) -> (S, HandshakeInfo) {
+        (self.tcp, self.info)
+    }
+
+    /// Consumes the `Socks5Stream`, returning the inner socket.
+    pub fn into_inner(self) -> S {
         self.tcp
     }
 
+    /// Returns the information about the negotiation with the proxy server.
+    pub fn handshake_info(&self) -> &HandshakeInfo {
+        &self.info
+    }
+
+    /// Returns the authentication method selected by the proxy server.
+    pub fn negotiated_auth(&self) -> NegotiatedAuth {
+        self.info.auth
+    }
+
     /// Returns the target address that the proxy server connects to.
     pub fn target_addr(&self) -> TargetAddr {
-        match &self.target {
+        match &self.info.target {
             TargetAddr::Ip(addr) => TargetAddr::Ip(*addr),
             TargetAddr::Domain(domain, port) => {
                 let domain: &str = domain.borrow();
@@ -110,289 +546,860 @@ impl Socks5Stream {
             }
         }
     }
+
+    /// Returns the address of the proxy server this stream is connected to.
+    ///
+    /// It is the unspecified address `0.0.0.0:0` if the negotiation was run with
+    /// `connect_with_socket` or `connect_unix`, since the socket has no IP peer address.
+    pub fn proxy_addr(&self) -> SocketAddr {
+        self.info.proxy_addr
+    }
+}
+
+impl<S> Socks5Stream<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    /// Connects to a target server through a SOCKS5 proxy, running the negotiation on an
+    /// established connection to the proxy server.
+    ///
+    /// `stream` can be any transport, e.g. a TLS stream to the proxy server, a tunnel
+    /// through another proxy or an in-memory pipe in tests. For providers which offer
+    /// SOCKS5 over TLS, `tls::connect_tls` with the `rustls` feature, or
+    /// `tls::connect_native_tls` with the `native-tls` feature, does the TLS handshake
+    /// before the negotiation. For another TLS library, connect to the proxy server, run
+    /// the TLS handshake and pass the TLS stream here.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_socket<'t, T>(
+        stream: S,
+        target: T,
+    ) -> Result<SocketConnectFuture<'static, 't, S>>
+    where
+        T: IntoTargetAddr<'t>,
+    {
+        Self::connect_with_socket_raw(stream, target, Authentication::None)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using given username and
+    /// password, running the negotiation on an established connection to the proxy server.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+    /// fails if the length of `username` or `password` is not between 1 and 255.
+    pub fn connect_with_socket_and_password<'a, 't, T>(
+        stream: S,
+        target: T,
+        username: &'a str,
+        password: &'a str,
+    ) -> Result<SocketConnectFuture<'a, 't, S>>
+    where
+        T: IntoTargetAddr<'t>,
+    {
+        let auth = Authentication::Password {
+            username: username.as_bytes().into(),
+            password: password.as_bytes().into(),
+        };
+        Self::connect_with_socket_raw(stream, target, auth)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy using a custom authentication
+    /// method, running the negotiation on an established connection to the proxy server.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_with_socket_and_auth<'t, T, A>(
+        stream: S,
+        target: T,
+        method: A,
+    ) -> Result<SocketConnectFuture<'static, 't, S>>
+    where
+        T: IntoTargetAddr<'t>,
+        A: AuthMethod<S> + 'static,
+    {
+        Self::connect_with_socket_raw(stream, target, Authentication::Custom(Arc::new(method)))
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy, offering several authentication
+    /// methods in the order of preference, running the negotiation on an established
+    /// connection to the proxy server.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`. It
+    /// fails if `methods` is empty, offers a method twice or contains an invalid username or
+    /// password.
+    pub fn connect_with_socket_and_methods<'a, 't, T, M>(
+        stream: S,
+        target: T,
+        methods: M,
+    ) -> Result<SocketConnectFuture<'a, 't, S>>
+    where
+        T: IntoTargetAddr<'t>,
+        M: IntoIterator<Item = Method<'a, S>>,
+    {
+        let methods = methods.into_iter().map(Authentication::from).collect();
+        Self::connect_with_socket_raw(stream, target, Authentication::Multiple(methods))
+    }
+
+    fn connect_with_socket_raw<'a, 't, T>(
+        stream: S,
+        target: T,
+        auth: Authentication<'a, S>,
+    ) -> Result<SocketConnectFuture<'a, 't, S>>
+    where
+        T: IntoTargetAddr<'t>,
+    {
+        validate_auth(&auth)?;
+        Ok(SocketConnectFuture::new(
+            stream,
+            auth,
+            target.into_target_addr()?,
+        ))
+    }
+}
+
+#[cfg(all(unix, feature = "unix"))]
+impl Socks5Stream<UnixStream> {
+    /// Connects to a target server through a SOCKS5 proxy listening on the Unix domain
+    /// socket at `path`, e.g. `/run/tor/socks`.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_unix<'t, P, T>(path: P, target: T) -> Result<UnixConnectFuture<'static, 't>>
+    where
+        P: AsRef<std::path::Path>,
+        T: IntoTargetAddr<'t>,
+    {
+        Self::connect_unix_raw(path, target, Authentication::None)
+    }
+
+    /// Connects to a target server through a SOCKS5 proxy listening on the Unix domain
+    /// socket at `path` using given username and password.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+    /// fails if the length of `username` or `password` is not between 1 and 255.
+    pub fn connect_unix_with_password<'a, 't, P, T>(
+        path: P,
+        target: T,
+        username: &'a str,
+        password: &'a str,
+    ) -> Result<UnixConnectFuture<'a, 't>>
+    where
+        P: AsRef<std::path::Path>,
+        T: IntoTargetAddr<'t>,
+    {
+        let auth = Authentication::Password {
+            username: username.as_bytes().into(),
+            password: password.as_bytes().into(),
+        };
+        Self::connect_unix_raw(path, target, auth)
+    }
+
+    fn connect_unix_raw<'a, 't, P, T>(
+        path: P,
+        target: T,
+        auth: Authentication<'a, UnixStream>,
+    ) -> Result<UnixConnectFuture<'a, 't>>
+    where
+        P: AsRef<std::path::Path>,
+        T: IntoTargetAddr<'t>,
+    {
+        validate_auth(&auth)?;
+        Ok(UnixConnectFuture {
+            conn: Some(UnixStream::connect(path)),
+            negotiation: Negotiation::new(auth, Command::Connect, target.into_target_addr()?),
+        })
+    }
+}
+
+pub(crate) fn validate_auth<S>(auth: &Authentication<S>) -> Result<()> {
+    if let Authentication::Multiple(methods) = auth {
+        if methods.is_empty() || methods.len() > 255 {
+            Err(Error::InvalidAuthValues(
+                "number of auth methods should between 1 to 255",
+            ))?
+        }
+        for (i, method) in methods.iter().enumerate() {
+            if methods[..i].iter().any(|m| m.id() == method.id()) {
+                Err(Error::InvalidAuthValues("auth method is offered twice"))?
+            }
+            validate_auth(method)?;
+        }
+    }
+    if let Authentication::Custom(method) = auth {
+        // These IDs mean no authentication, username/password and "no acceptable methods".
+        if let 0x00 | 0x02 | 0xff = method.id() {
+            Err(Error::InvalidAuthValues(
+                "custom auth method ID is reserved",
+            ))?
+        }
+    }
+    if let Some((username, password)) = auth.password() {
+        let username_len = username.len();
+        if username_len < 1 || username_len > 255 {
+            Err(Error::InvalidAuthValues(
+                "username length should between 1 to 255",
+            ))?
+        }
+        let password_len = password.len();
+        if password_len < 1 || password_len > 255 {
+            Err(Error::InvalidAuthValues(
+                "password length should between 1 to 255",
+            ))?
+        }
+    }
+    Ok(())
+}
+
+/// A `Future` which resolves to the IP address of a domain resolved through a Tor proxy.
+#[cfg(feature = "tor")]
+pub type TorResolveFuture<'t, S> = futures::future::AndThen<
+    ConnectFuture<'static, 't, S>,
+    Result<std::net::IpAddr>,
+    fn(Socks5Stream) -> Result<std::net::IpAddr>,
+>;
+
+#[cfg(feature = "tor")]
+fn resolved_ip(stream: Socks5Stream) -> Result<std::net::IpAddr> {
+    match stream.info.target {
+        TargetAddr::Ip(addr) => Ok(addr.ip()),
+        TargetAddr::Domain(..) => Err(Error::InvalidTargetAddress(
+            "resolved address is not an IP address",
+        )),
+    }
+}
+
+/// A `Future` which resolves to the hostname of an IP address resolved through a Tor proxy.
+#[cfg(feature = "tor")]
+pub type TorResolvePtrFuture<S> = futures::future::AndThen<
+    ConnectFuture<'static, 'static, S>,
+    Result<String>,
+    fn(Socks5Stream) -> Result<String>,
+>;
+
+#[cfg(feature = "tor")]
+fn resolved_hostname(stream: Socks5Stream) -> Result<String> {
+    match stream.info.target {
+        TargetAddr::Domain(domain, _) => Ok(domain.into_owned()),
+        TargetAddr::Ip(..) => Err(Error::InvalidTargetAddress(
+            "resolved address is not a hostname",
+        )),
+    }
+}
+
+/// A `Future` which resolves to a connection which has finished the SOCKS5 negotiation and
+/// the information about the negotiation.
+pub type HandshakeFuture<'a, 't, S = TcpStream> =
+    Map<SocketConnectFuture<'a, 't, S>, fn(Socks5Stream<S>) -> (S, HandshakeInfo)>;
+
+/// Performs the SOCKS5 negotiation to connect to a target server on an established
+/// connection to the proxy server, offering `auth` to it.
+///
+/// The connection is handed back as is, without any wrapper type. It can be any transport,
+/// so `proxy_addr` of the returned `HandshakeInfo` is the unspecified address `0.0.0.0:0`.
+///
+/// # Error
+///
+/// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+/// fails if the length of a username or password is not between 1 and 255.
+pub fn handshake_only<'a, 't, S, T>(
+    stream: S,
+    target: T,
+    auth: Method<'a, S>,
+) -> Result<HandshakeFuture<'a, 't, S>>
+where
+    S: AsyncRead + AsyncWrite,
+    T: IntoTargetAddr<'t>,
+{
+    let conn = Socks5Stream::connect_with_socket_raw(stream, target, Authentication::from(auth))?;
+    Ok(conn.map(Socks5Stream::into_parts as fn(_) -> _))
+}
+
+/// Implements the methods which configure the negotiation for a connect future with a
+/// `negotiation` field.
+macro_rules! negotiation_builders {
+    () => {
+        /// Sends `data` to the target server right after the request, without waiting for
+        /// the reply of the proxy server.
+        ///
+        /// The request and `data` are written to the socket together when possible, which
+        /// saves a write and a round trip for protocols where the client speaks first, e.g.
+        /// a TLS ClientHello or an HTTP request. If the proxy server rejects the request,
+        /// `data` is discarded by it.
+        pub fn with_initial_data<D: Into<Vec<u8>>>(mut self, data: D) -> Self {
+            self.negotiation.initial_data = data.into();
+            self
+        }
+
+        /// Validates the reply of the proxy server strictly, for applications which layer a
+        /// protocol such as TLS on top and must not be confused by bytes smuggled into the
+        /// handshake.
+        ///
+        /// Besides the checks which are always done, e.g. of the reserved byte and the
+        /// address type, it rejects an empty domain name in the reply and fails with
+        /// `UnexpectedDataAfterReply` if more data has already arrived when the reply is
+        /// complete. Only use it for protocols where the client speaks first, and not
+        /// together with `with_initial_data`, since the target server may legitimately
+        /// answer early.
+        pub fn with_strict_reply(mut self) -> Self {
+            self.negotiation.strict = true;
+            self
+        }
+
+        /// Limits the length of a domain name in the reply of the proxy server to `max`
+        /// bytes.
+        ///
+        /// A longer domain name fails the negotiation with `ReplyDomainTooLong` before it is
+        /// read. The default is 255, the most the protocol allows.
+        pub fn with_max_reply_domain_len(mut self, max: u8) -> Self {
+            self.negotiation.max_domain_len = max;
+            self
+        }
+
+        /// Allows a CONNECT request to port 0 of the target.
+        ///
+        /// No server listens on port 0, so such a target is almost always a mistake, e.g. a
+        /// port which was never filled in, and the future fails with
+        /// `InvalidTargetAddress` before connecting to the proxy server. Use it with
+        /// proxy servers which give port 0 a meaning of their own.
+        pub fn allow_port_zero(mut self) -> Self {
+            self.negotiation.allow_port_zero = true;
+            self
+        }
+
+        /// Returns a `Stream` of the steps of the negotiation, e.g. to show the progress of
+        /// a slow proxy server in a user interface.
+        ///
+        /// Each step is reported when it begins, and the stream ends when the future
+        /// completes or is dropped. If a proxy address fails, the steps start over from
+        /// `Resolving` with the next one. Calling this again replaces the previous stream.
+        pub fn progress(&mut self) -> UnboundedReceiver<ConnectProgress> {
+            let (tx, rx) = mpsc::unbounded();
+            self.negotiation.progress = ProgressSender(Some((tx, None)));
+            rx
+        }
+    };
 }
 
 /// A `Future` which resolves to a socket to the target server through proxy.
+///
+/// The proxy addresses are tried in order until a connection is established. If the proxy
+/// server replies with "TTL expired" to a CONNECT request, the next proxy address is tried
+/// once more, unless it is turned off with `retry_on_ttl_expired`.
 pub struct ConnectFuture<'a, 't, S>
 where
     S: Stream<Item = SocketAddr, Error = Error>,
 {
-    auth: Authentication<'a>,
-    command: Command,
     proxy: S,
-    target: TargetAddr<'t>,
-    state: ConnectState,
-    buf: [u8; 513],
-    ptr: usize,
-    len: usize,
+    retry_ttl_expired: bool,
+    ttl_expired: bool,
+    conn: Option<(SocketAddr, TokioConnect)>,
+    negotiation: Negotiation<'a, 't, TcpStream>,
 }
 
 impl<'a, 't, S> ConnectFuture<'a, 't, S>
 where
     S: Stream<Item = SocketAddr, Error = Error>,
 {
-    fn new(auth: Authentication<'a>, command: Command, proxy: S, target: TargetAddr<'t>) -> Self {
+    pub(crate) fn new(
+        auth: Authentication<'a>,
+        command: Command,
+        proxy: S,
+        target: TargetAddr<'t>,
+    ) -> Self {
         ConnectFuture {
+            proxy,
+            retry_ttl_expired: true,
+            ttl_expired: false,
+            conn: None,
+            negotiation: Negotiation::new(auth, command, target),
+        }
+    }
+
+    negotiation_builders!();
+
+    /// Sets whether the next proxy address is tried once more when the proxy server replies
+    /// to a CONNECT request with "TTL expired", which is often a routing blip behind the
+    /// proxy.
+    ///
+    /// It is on by default. It has no effect on other requests, e.g. BIND or UDP
+    /// ASSOCIATE, which always fail with `TtlExpired`.
+    pub fn retry_on_ttl_expired(mut self, enabled: bool) -> Self {
+        self.retry_ttl_expired = enabled;
+        self
+    }
+
+    /// Returns whether the negotiation failed before the proxy server answered the greeting,
+    /// i.e. before anything but the offered methods was sent.
+    #[cfg(feature = "v4")]
+    pub(crate) fn failed_in_method_selection(&self) -> bool {
+        matches!(
+            self.negotiation.failed_step,
+            Some(Step::SendMethodSelection) | Some(Step::RecvMethodSelection)
+        )
+    }
+}
+
+impl<'a, 't, S> Future for ConnectFuture<'a, 't, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    type Item = Socks5Stream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Socks5Stream, Error> {
+        loop {
+            if self.negotiation.is_started() {
+                match self.negotiation.poll() {
+                    Err(Error::TtlExpired)
+                        if self.retry_ttl_expired
+                            && !self.ttl_expired
+                            && self.negotiation.command == Command::Connect =>
+                    {
+                        warn!("SOCKS5 proxy replied TTL expired, retrying once");
+                        // Often a routing blip behind the proxy, so try the next proxy
+                        // address once before giving up.
+                        self.ttl_expired = true;
+                    }
+                    res => return res,
+                }
+            } else if let Some((addr, conn)) = &mut self.conn {
+                match conn.poll() {
+                    Ok(Async::Ready(tcp)) => {
+                        let addr = *addr;
+                        self.conn = None;
+                        self.negotiation.start(tcp, addr);
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(_e) => {
+                        debug!(
+                            "failed to connect to SOCKS5 proxy {}: {}",
+                            LogAddr::from(*addr),
+                            _e
+                        );
+                        self.conn = None;
+                    }
+                }
+            } else {
+                self.negotiation.check_target()?;
+                self.negotiation.progress.report(ConnectProgress::Resolving);
+                match try_ready!(self.proxy.poll()) {
+                    Some(addr) => {
+                        debug!("connecting to SOCKS5 proxy {}", LogAddr::from(addr));
+                        let progress = &mut self.negotiation.progress;
+                        progress.report(ConnectProgress::Connecting(addr));
+                        self.conn = Some((addr, TcpStream::connect(&addr)));
+                    }
+                    None if self.ttl_expired => Err(Error::TtlExpired)?,
+                    None => Err(Error::ProxyServerUnreachable)?,
+                }
+            }
+        }
+    }
+}
+
+/// The SOCKS5 negotiation on an established connection to the proxy server, which every
+/// connect future drives once it has a connection.
+pub(crate) struct Negotiation<'a, 't, S> {
+    auth: Authentication<'a, S>,
+    command: Command,
+    target: TargetAddr<'t>,
+    proxy_addr: SocketAddr,
+    socket: Option<S>,
+    custom_auth: Option<AuthFuture<S>>,
+    step: Step,
+    /// The step which the negotiation failed in, if it did.
+    failed_step: Option<Step>,
+    initial_data: Vec<u8>,
+    strict: bool,
+    max_domain_len: u8,
+    allow_port_zero: bool,
+    negotiated_auth: NegotiatedAuth,
+    progress: ProgressSender,
+    buf: [u8; 513],
+    ptr: usize,
+    len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    NotStarted,
+    SendMethodSelection,
+    RecvMethodSelection,
+    CustomAuth,
+    SendPasswordAuth,
+    RecvPasswordAuth,
+    SendRequest,
+    RecvReply,
+    RecvDomainLen,
+    RecvAddress,
+}
+
+impl<'a, 't, S> Negotiation<'a, 't, S> {
+    pub(crate) fn new(
+        auth: Authentication<'a, S>,
+        command: Command,
+        target: TargetAddr<'t>,
+    ) -> Self {
+        Negotiation {
             auth,
             command,
-            proxy,
             target,
-            state: ConnectState::Uninitialized,
+            // An arbitrary socket has no IP peer address.
+            proxy_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            socket: None,
+            custom_auth: None,
+            step: Step::NotStarted,
+            failed_step: None,
+            initial_data: Vec::new(),
+            strict: false,
+            max_domain_len: 255,
+            allow_port_zero: false,
+            negotiated_auth: NegotiatedAuth::None,
+            progress: ProgressSender(None),
             buf: [0; 513],
             ptr: 0,
             len: 0,
         }
     }
 
-    fn prepare_send_method_selection(&mut self) {
+    /// Starts the negotiation on `socket`, a connection to the proxy server at
+    /// `proxy_addr`.
+    fn start(&mut self, socket: S, proxy_addr: SocketAddr) {
+        self.proxy_addr = proxy_addr;
+        self.socket = Some(socket);
+        self.step = Step::SendMethodSelection;
         self.ptr = 0;
-        self.buf[0] = 0x05;
-        match self.auth {
-            Authentication::None => {
-                self.buf[1..3].copy_from_slice(&[1, 0x00]);
-                self.len = 3;
-            }
-            Authentication::Password { .. } => {
-                self.buf[1..4].copy_from_slice(&[2, 0x00, 0x02]);
-                self.len = 4;
-            }
-        }
+        self.len = encode_method_selection(&self.auth, &mut self.buf);
     }
 
-    fn prepare_recv_method_selection(&mut self) {
+    /// Waits for another reply on `socket`, which has already finished a negotiation, e.g.
+    /// for the second reply of a BIND request.
+    fn wait_for_reply(&mut self, socket: S, proxy_addr: SocketAddr) {
+        self.proxy_addr = proxy_addr;
+        self.socket = Some(socket);
+        self.step = Step::RecvReply;
         self.ptr = 0;
-        self.len = 2;
+        self.len = 4;
     }
 
-    fn prepare_send_password_auth(&mut self) {
-        if let Authentication::Password { username, password } = self.auth {
-            self.ptr = 0;
-            self.buf[0] = 0x01;
-            let username_bytes = username.as_bytes();
-            let username_len = username_bytes.len();
-            self.buf[1] = username_len as u8;
-            self.buf[2..(2 + username_len)].copy_from_slice(username_bytes);
-            let password_bytes = password.as_bytes();
-            let password_len = password_bytes.len();
-            self.len = 3 + username_len + password_len;
-            self.buf[(2 + username_len)] = password_len as u8;
-            self.buf[(3 + username_len)..self.len].copy_from_slice(password_bytes);
-        } else {
-            unreachable!()
+    /// Fails a CONNECT request to port 0 unless `allow_port_zero` was called.
+    fn check_target(&self) -> Result<()> {
+        if self.command == Command::Connect && !self.allow_port_zero && self.target.port() == 0 {
+            Err(Error::InvalidTargetAddress(
+                "port 0 is not a valid target port",
+            ))?
         }
+        Ok(())
     }
 
-    fn prepare_recv_password_auth(&mut self) {
-        self.ptr = 0;
-        self.len = 2;
+    /// Returns whether the negotiation has a connection to run on. It stops having one once
+    /// it completes or fails.
+    fn is_started(&self) -> bool {
+        self.step != Step::NotStarted
     }
 
     fn prepare_send_request(&mut self) {
+        self.progress.report(ConnectProgress::Requesting);
+        self.step = Step::SendRequest;
         self.ptr = 0;
-        self.buf[..3].copy_from_slice(&[0x05, self.command as u8, 0x00]);
-        match &self.target {
-            TargetAddr::Ip(SocketAddr::V4(addr)) => {
-                self.buf[3] = 0x01;
-                self.buf[4..8].copy_from_slice(&addr.ip().octets());
-                self.buf[8..10].copy_from_slice(&addr.port().to_be_bytes());
-                self.len = 10;
-            }
-            TargetAddr::Ip(SocketAddr::V6(addr)) => {
-                self.buf[3] = 0x04;
-                self.buf[4..20].copy_from_slice(&addr.ip().octets());
-                self.buf[20..22].copy_from_slice(&addr.port().to_be_bytes());
-                self.len = 22;
-            }
-            TargetAddr::Domain(domain, port) => {
-                self.buf[3] = 0x03;
-                let domain = domain.as_bytes();
-                let len = domain.len();
-                self.buf[4] = len as u8;
-                self.buf[5..5 + len].copy_from_slice(domain);
-                self.buf[(5 + len)..(7 + len)].copy_from_slice(&port.to_be_bytes());
-                self.len = 7 + len;
-            }
-        }
-    }
-
-    fn prepare_recv_reply(&mut self) {
-        self.ptr = 0;
-        self.len = 4;
+        self.len = encode_request(self.command, &self.target, &mut self.buf);
     }
 }
 
-impl<'a, 't, S> Future for ConnectFuture<'a, 't, S>
+impl<'a, 't, S> Future for Negotiation<'a, 't, S>
 where
-    S: Stream<Item = SocketAddr, Error = Error>,
+    S: AsyncRead + AsyncWrite,
 {
-    type Item = Socks5Stream;
+    type Item = Socks5Stream<S>;
     type Error = Error;
 
-    fn poll(&mut self) -> Poll<Socks5Stream, Error> {
+    fn poll(&mut self) -> Poll<Socks5Stream<S>, Error> {
+        let res = self.poll_steps();
+        if res.is_err() {
+            self.failed_step = Some(self.step);
+            self.step = Step::NotStarted;
+            self.socket = None;
+            self.custom_auth = None;
+        }
+        res
+    }
+}
+
+impl<'a, 't, S> Negotiation<'a, 't, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    fn poll_steps(&mut self) -> Poll<Socks5Stream<S>, Error> {
+        if self.step == Step::SendMethodSelection && self.ptr == 0 {
+            self.check_target()?;
+            self.progress.report(ConnectProgress::Negotiating);
+        }
         loop {
-            match self.state {
-                ConnectState::Uninitialized => match try_ready!(self.proxy.poll()) {
-                    Some(addr) => self.state = ConnectState::Created(TcpStream::connect(&addr)),
-                    None => Err(Error::ProxyServerUnreachable)?,
-                },
-                ConnectState::Created(ref mut conn_fut) => match conn_fut.poll() {
-                    Ok(Async::Ready(tcp)) => {
-                        self.state = ConnectState::Connected(Some(tcp));
-                        self.prepare_send_method_selection()
-                    }
-                    Ok(Async::NotReady) => return Ok(Async::NotReady),
-                    Err(_e) => self.state = ConnectState::Uninitialized,
-                },
-                ConnectState::Connected(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_write(&self.buf[self.ptr..self.len]));
-                    ;
-                    if self.ptr == self.len {
-                        self.state = ConnectState::MethodSent(opt.take());
-                        self.prepare_recv_method_selection();
-                    }
+            if self.step == Step::CustomAuth {
+                let socket = try_ready!(self.custom_auth.as_mut().unwrap().poll());
+                self.custom_auth = None;
+                self.socket = Some(socket);
+                self.prepare_send_request();
+            }
+            let socket = self.socket.as_mut().expect("polled after completion");
+            let end = match self.step {
+                Step::SendMethodSelection | Step::SendPasswordAuth => {
+                    self.ptr += try_ready!(poll_write_some(socket, &self.buf[self.ptr..self.len]));
+                    self.len
                 }
-                ConnectState::MethodSent(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_read(&mut self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        if self.buf[0] != 0x05 {
-                            Err(Error::InvalidResponseVersion)?
-                        }
-                        match self.buf[1] {
-                            0x00 => self.state = ConnectState::PrepareRequest(opt.take()),
-                            0xff => Err(Error::NoAcceptableAuthMethods)?,
-                            0x02 => {
-                                self.state = ConnectState::PasswordAuth(opt.take());
-                                self.prepare_send_password_auth();
-                            }
-                            m if m != self.auth.id() => Err(Error::UnknownAuthMethod)?,
-                            _ => unimplemented!(),
-                        }
-                    }
+                Step::SendRequest => {
+                    let request = &self.buf[self.ptr.min(self.len)..self.len];
+                    let data = &self.initial_data[self.ptr.saturating_sub(self.len)..];
+                    self.ptr += try_ready!(poll_write_chain(socket, request, data));
+                    self.len + self.initial_data.len()
                 }
-                ConnectState::PasswordAuth(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_write(&self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        self.state = ConnectState::PasswordAuthSent(opt.take());
-                        self.prepare_recv_password_auth();
-                    }
+                _ => {
+                    self.ptr +=
+                        try_ready!(poll_read_some(socket, &mut self.buf[self.ptr..self.len]));
+                    self.len
                 }
-                ConnectState::PasswordAuthSent(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_read(&mut self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        if self.buf[0] != 0x01 {
-                            Err(Error::InvalidResponseVersion)?
-                        }
-                        if self.buf[1] != 0x00 {
-                            Err(Error::PasswordAuthFailure(self.buf[1]))?
-                        }
-                        self.state = ConnectState::PrepareRequest(opt.take());
+            };
+            if self.ptr < end {
+                continue;
+            }
+            match self.step {
+                Step::SendMethodSelection => {
+                    self.step = Step::RecvMethodSelection;
+                    self.ptr = 0;
+                    self.len = 2;
+                }
+                Step::RecvMethodSelection => {
+                    let sub_negotiation = check_method_selection(&self.auth, &self.buf[..2])?;
+                    self.negotiated_auth = NegotiatedAuth::from_method(self.buf[1]);
+                    debug!("SOCKS5 proxy selected {:?}", self.negotiated_auth);
+                    if !sub_negotiation {
+                        self.prepare_send_request();
+                        continue;
                     }
+                    self.progress.report(ConnectProgress::Authenticating);
+                    let selected = self.auth.selected(self.buf[1]);
+                    if let Authentication::Custom(method) = selected {
+                        let socket = self.socket.take().unwrap();
+                        self.custom_auth = Some(method.authenticate(socket));
+                        self.step = Step::CustomAuth;
+                    } else {
+                        self.step = Step::SendPasswordAuth;
+                        self.ptr = 0;
+                        self.len = encode_password_auth(selected, &mut self.buf);
+                    }
+                }
+                Step::SendPasswordAuth => {
+                    self.step = Step::RecvPasswordAuth;
+                    self.ptr = 0;
+                    self.len = 2;
                 }
-                ConnectState::PrepareRequest(ref mut opt) => {
-                    self.state = ConnectState::SendRequest(opt.take());
+                Step::RecvPasswordAuth => {
+                    check_password_auth(&self.buf[..2])?;
                     self.prepare_send_request();
                 }
-                ConnectState::SendRequest(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_write(&self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        self.state = ConnectState::RequestSent(opt.take());
-                        self.prepare_recv_reply();
-                    }
+                Step::SendRequest => {
+                    self.step = Step::RecvReply;
+                    self.ptr = 0;
+                    self.len = 4;
                 }
-                ConnectState::RequestSent(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_read(&mut self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        if self.buf[0] != 0x05 {
-                            Err(Error::InvalidResponseVersion)?
-                        }
-                        if self.buf[2] != 0x00 {
-                            Err(Error::InvalidReservedByte)?
+                Step::RecvReply => {
+                    check_reply(&self.buf[..4])?;
+                    match self.buf[3] {
+                        // IPv4
+                        0x01 => {
+                            self.step = Step::RecvAddress;
+                            self.len = 10;
                         }
-                        match self.buf[1] {
-                            0x00 => {} // succeeded
-                            0x01 => Err(Error::GeneralSocksServerFailure)?,
-                            0x02 => Err(Error::ConnectionNotAllowedByRuleset)?,
-                            0x03 => Err(Error::NetworkUnreachable)?,
-                            0x04 => Err(Error::HostUnreachable)?,
-                            0x05 => Err(Error::ConnectionRefused)?,
-                            0x06 => Err(Error::TtlExpired)?,
-                            0x07 => Err(Error::CommandNotSupported)?,
-                            0x08 => Err(Error::AddressTypeNotSupported)?,
-                            _ => Err(Error::UnknownAuthMethod)?,
+                        // IPv6
+                        0x04 => {
+                            self.step = Step::RecvAddress;
+                            self.len = 22;
                         }
-                        match self.buf[3] {
-                            // IPv4
-                            0x01 => {
-                                self.len = 10;
-                                self.state = ConnectState::ReadAddress(opt.take())
-                            }
-                            // IPv6
-                            0x04 => {
-                                self.len = 22;
-                                self.state = ConnectState::ReadAddress(opt.take())
-                            }
-                            // Domain
-                            0x03 => {
-                                self.len = 5;
-                                self.state = ConnectState::PrepareReadAddress(opt.take())
-                            }
-                            _ => Err(Error::UnknownAddressType)?,
+                        // Domain
+                        0x03 => {
+                            self.step = Step::RecvDomainLen;
+                            self.len = 5;
                         }
+                        atyp => Err(Error::UnknownAddressType(atyp))?,
                     }
                 }
-                ConnectState::PrepareReadAddress(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_read(&mut self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        self.len += self.buf[4] as usize + 2;
-                        self.state = ConnectState::ReadAddress(opt.take());
+                Step::RecvDomainLen => {
+                    if self.buf[4] > self.max_domain_len {
+                        Err(Error::ReplyDomainTooLong(self.buf[4]))?
                     }
+                    self.step = Step::RecvAddress;
+                    self.len += self.buf[4] as usize + 2;
                 }
-                ConnectState::ReadAddress(ref mut opt) => {
-                    let tcp = opt.as_mut().unwrap();
-                    self.ptr += try_ready!(tcp.poll_read(&mut self.buf[self.ptr..self.len]));
-                    if self.ptr == self.len {
-                        let target: TargetAddr<'static> = match self.buf[3] {
-                            // IPv4
-                            0x01 => {
-                                let mut ip = [0; 4];
-                                ip[..].copy_from_slice(&self.buf[4..8]);
-                                let ip = Ipv4Addr::from(ip);
-                                let port = u16::from_be_bytes([self.buf[8], self.buf[9]]);
-                                (ip, port).into_target_addr()?
-                            }
-                            // IPv6
-                            0x04 => {
-                                let mut ip = [0; 16];
-                                ip[..].copy_from_slice(&self.buf[4..20]);
-                                let ip = Ipv6Addr::from(ip);
-                                let port = u16::from_be_bytes([self.buf[20], self.buf[21]]);
-                                (ip, port).into_target_addr()?
-                            }
-                            // Domain
-                            0x03 => {
-                                let domain_bytes = (&self.buf[5..(self.len - 2)]).to_vec();
-                                let domain = String::from_utf8(domain_bytes).map_err(|_| {
-                                    Error::InvalidTargetAddress("not a valid UTF-8 string")
-                                })?;
-                                let port = u16::from_be_bytes([
-                                    self.buf[self.len - 2],
-                                    self.buf[self.len - 1],
-                                ]);
-                                TargetAddr::Domain(domain.into(), port)
-                            }
-                            _ => unreachable!(),
-                        };
-                        return Ok(Async::Ready(Socks5Stream {
-                            tcp: opt.take().unwrap(),
+                Step::RecvAddress => {
+                    let target = parse_address(&self.buf[3..self.len])?;
+                    if self.strict {
+                        check_strict_reply(socket, &target)?;
+                    }
+                    debug!(
+                        "SOCKS5 proxy {} accepted {:?} for {}",
+                        LogAddr::from(self.proxy_addr),
+                        self.command,
+                        LogAddr::from(&self.target)
+                    );
+                    self.progress.report(ConnectProgress::Established);
+                    self.step = Step::NotStarted;
+                    return Ok(Async::Ready(Socks5Stream {
+                        tcp: self.socket.take().unwrap(),
+                        info: HandshakeInfo {
+                            proxy_addr: self.proxy_addr,
+                            requested_target: self.target.to_owned(),
                             target,
-                        }));
+                            auth: self.negotiated_auth,
+                        },
+                    }));
+                }
+                Step::NotStarted | Step::CustomAuth => unreachable!(),
+            }
+        }
+    }
+}
+
+/// A `Future` which resolves to a `Socks5Stream` negotiated on an established connection.
+///
+/// This `struct` is created by `Socks5Stream::connect_with_socket` and its variants.
+pub struct SocketConnectFuture<'a, 't, S> {
+    negotiation: Negotiation<'a, 't, S>,
+}
+
+impl<'a, 't, S> SocketConnectFuture<'a, 't, S> {
+    pub(crate) fn new(socket: S, auth: Authentication<'a, S>, target: TargetAddr<'t>) -> Self {
+        let mut negotiation = Negotiation::new(auth, Command::Connect, target);
+        let proxy_addr = negotiation.proxy_addr;
+        negotiation.start(socket, proxy_addr);
+        SocketConnectFuture { negotiation }
+    }
+
+    /// Sets the address of the proxy server which `socket` is connected to, for the
+    /// `Socks5Stream` it resolves to.
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    pub(crate) fn with_proxy_addr(mut self, proxy_addr: SocketAddr) -> Self {
+        self.negotiation.proxy_addr = proxy_addr;
+        self
+    }
+
+    negotiation_builders!();
+}
+
+impl<'a, 't, S> Future for SocketConnectFuture<'a, 't, S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    type Item = Socks5Stream<S>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Socks5Stream<S>, Error> {
+        self.negotiation.poll()
+    }
+}
+
+/// A `Future` which resolves to a socket to the target server through a proxy listening on
+/// a Unix domain socket.
+///
+/// This `struct` is created by `Socks5Stream::connect_unix` and its variants.
+#[cfg(all(unix, feature = "unix"))]
+pub struct UnixConnectFuture<'a, 't> {
+    conn: Option<tokio_uds::ConnectFuture>,
+    negotiation: Negotiation<'a, 't, UnixStream>,
+}
+
+#[cfg(all(unix, feature = "unix"))]
+impl<'a, 't> UnixConnectFuture<'a, 't> {
+    negotiation_builders!();
+}
+
+#[cfg(all(unix, feature = "unix"))]
+impl<'a, 't> Future for UnixConnectFuture<'a, 't> {
+    type Item = Socks5Stream<UnixStream>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Error> {
+        if let Some(conn) = &mut self.conn {
+            self.negotiation.check_target()?;
+            let unix = try_ready!(conn.poll());
+            self.conn = None;
+            let proxy_addr = self.negotiation.proxy_addr;
+            self.negotiation.start(unix, proxy_addr);
+        }
+        self.negotiation.poll()
+    }
+}
+
+/// A `Future` which resolves to a socket to the target server through a proxy, with the
+/// connection to the proxy server wrapped by a `Layer`.
+///
+/// This `struct` is created by `Socks5Stream::connect_layered` and its variants.
+pub struct LayeredConnectFuture<'a, 't, S, L>
+where
+    L: Layer,
+{
+    proxy: S,
+    layer: L,
+    conn: Option<(SocketAddr, TokioConnect)>,
+    negotiation: Negotiation<'a, 't, L::Output>,
+}
+
+impl<'a, 't, S, L> LayeredConnectFuture<'a, 't, S, L>
+where
+    L: Layer,
+{
+    negotiation_builders!();
+}
+
+impl<'a, 't, S, L> Future for LayeredConnectFuture<'a, 't, S, L>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+    L: Layer,
+{
+    type Item = Socks5Stream<L::Output>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Error> {
+        loop {
+            if self.negotiation.is_started() {
+                return self.negotiation.poll();
+            }
+            match &mut self.conn {
+                Some((addr, conn)) => match conn.poll() {
+                    Ok(Async::Ready(tcp)) => {
+                        let addr = *addr;
+                        self.conn = None;
+                        self.negotiation.start(self.layer.wrap(tcp), addr);
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(_e) => {
+                        debug!(
+                            "failed to connect to SOCKS5 proxy {}: {}",
+                            LogAddr::from(*addr),
+                            _e
+                        );
+                        self.conn = None;
+                    }
+                },
+                None => {
+                    self.negotiation.check_target()?;
+                    self.negotiation.progress.report(ConnectProgress::Resolving);
+                    match try_ready!(self.proxy.poll()) {
+                        Some(addr) => {
+                            debug!("connecting to SOCKS5 proxy {}", LogAddr::from(addr));
+                            let progress = &mut self.negotiation.progress;
+                            progress.report(ConnectProgress::Connecting(addr));
+                            self.conn = Some((addr, TcpStream::connect(&addr)));
+                        }
+                        None => Err(Error::ProxyServerUnreachable)?,
                     }
                 }
             }
@@ -400,19 +1407,246 @@ where
     }
 }
 
-#[derive(Debug)]
-enum ConnectState {
-    Uninitialized,
-    Created(TokioConnect),
-    Connected(Option<TcpStream>),
-    MethodSent(Option<TcpStream>),
-    PasswordAuth(Option<TcpStream>),
-    PasswordAuthSent(Option<TcpStream>),
-    PrepareRequest(Option<TcpStream>),
-    SendRequest(Option<TcpStream>),
-    RequestSent(Option<TcpStream>),
-    PrepareReadAddress(Option<TcpStream>),
-    ReadAddress(Option<TcpStream>),
+/// Reads into `buf`, treating EOF as an error since `buf` is never empty during the
+/// negotiation. Otherwise the poll loop would spin forever on a closed connection.
+pub(crate) fn poll_read_some<S: AsyncRead>(tcp: &mut S, buf: &mut [u8]) -> Poll<usize, Error> {
+    match try_ready!(tcp.poll_read(buf)) {
+        0 => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "proxy server closed the connection",
+        ))?,
+        n => Ok(Async::Ready(n)),
+    }
+}
+
+/// Writes from `buf`, treating a zero-length write as an error for the same reason.
+pub(crate) fn poll_write_some<S: AsyncWrite>(tcp: &mut S, buf: &[u8]) -> Poll<usize, Error> {
+    match try_ready!(tcp.poll_write(buf)) {
+        0 => Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "failed to write to the proxy server",
+        ))?,
+        n => Ok(Async::Ready(n)),
+    }
+}
+
+/// Writes from `first` and then `second` in a single vectored write if possible.
+fn poll_write_chain<S: AsyncWrite>(tcp: &mut S, first: &[u8], second: &[u8]) -> Poll<usize, Error> {
+    if second.is_empty() {
+        return poll_write_some(tcp, first);
+    }
+    let mut buf = Buf::chain(first.into_buf(), second);
+    match try_ready!(AsyncWrite::write_buf(tcp, &mut buf)) {
+        0 => Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "failed to write to the proxy server",
+        ))?,
+        n => Ok(Async::Ready(n)),
+    }
+}
+
+/// Writes the method selection message offering the methods of `auth` into `buf`,
+/// returning its length.
+pub(crate) fn encode_method_selection<S>(auth: &Authentication<S>, buf: &mut [u8]) -> usize {
+    buf[0] = 0x05;
+    match auth {
+        Authentication::None => {
+            buf[1..3].copy_from_slice(&[1, 0x00]);
+            3
+        }
+        Authentication::Password { .. } | Authentication::SharedPassword(_) => {
+            buf[1..4].copy_from_slice(&[2, 0x00, 0x02]);
+            4
+        }
+        Authentication::Custom(method) => {
+            buf[1..3].copy_from_slice(&[1, method.id()]);
+            3
+        }
+        Authentication::Multiple(methods) => {
+            buf[1] = methods.len() as u8;
+            for (i, method) in methods.iter().enumerate() {
+                buf[2 + i] = method.id();
+            }
+            2 + methods.len()
+        }
+    }
+}
+
+/// Checks the method selection reply, returning whether the sub-negotiation of `auth`
+/// should follow.
+pub(crate) fn check_method_selection<S>(auth: &Authentication<S>, buf: &[u8]) -> Result<bool> {
+    // An HTTP proxy answers the greeting with `HTTP/1.x 400 ...`
+    if buf == b"HT" {
+        Err(Error::NotASocksProxy {
+            looks_like: DetectedProtocol::Http,
+        })?
+    }
+    if buf[0] != 0x05 {
+        Err(Error::InvalidResponseVersion {
+            expected: 0x05,
+            found: buf[0],
+        })?
+    }
+    match buf[1] {
+        0x00 if auth.offers(0x00) => Ok(false),
+        0xff => Err(Error::NoAcceptableAuthMethods),
+        m if auth.offers(m) => Ok(true),
+        _ => Err(Error::UnknownAuthMethod),
+    }
+}
+
+/// Writes the username/password authentication message into `buf`, returning its length.
+pub(crate) fn encode_password_auth<S>(auth: &Authentication<S>, buf: &mut [u8]) -> usize {
+    if let Some((username, password)) = auth.password() {
+        buf[0] = 0x01;
+        let username_len = username.len();
+        buf[1] = username_len as u8;
+        buf[2..(2 + username_len)].copy_from_slice(username);
+        let password_len = password.len();
+        let len = 3 + username_len + password_len;
+        buf[2 + username_len] = password_len as u8;
+        buf[(3 + username_len)..len].copy_from_slice(password);
+        len
+    } else {
+        unreachable!()
+    }
+}
+
+/// Checks the username/password authentication reply.
+pub(crate) fn check_password_auth(buf: &[u8]) -> Result<()> {
+    if buf[0] != 0x01 {
+        Err(Error::InvalidResponseVersion {
+            expected: 0x01,
+            found: buf[0],
+        })?
+    }
+    if buf[1] != 0x00 {
+        Err(Error::PasswordAuthFailure(buf[1]))?
+    }
+    Ok(())
+}
+
+/// Writes the request message into `buf`, returning its length.
+pub(crate) fn encode_request(command: Command, target: &TargetAddr, buf: &mut [u8]) -> usize {
+    buf[..3].copy_from_slice(&[0x05, command as u8, 0x00]);
+    3 + encode_address(target, &mut buf[3..])
+}
+
+/// Writes the address type, address and port of `target` into `buf`, returning the length.
+pub(crate) fn encode_address(target: &TargetAddr, buf: &mut [u8]) -> usize {
+    match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            buf[0] = 0x01;
+            buf[1..5].copy_from_slice(&addr.ip().octets());
+            buf[5..7].copy_from_slice(&addr.port().to_be_bytes());
+            7
+        }
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            buf[0] = 0x04;
+            buf[1..17].copy_from_slice(&addr.ip().octets());
+            buf[17..19].copy_from_slice(&addr.port().to_be_bytes());
+            19
+        }
+        TargetAddr::Domain(domain, port) => {
+            buf[0] = 0x03;
+            let domain = domain.as_bytes();
+            let len = domain.len();
+            buf[1] = len as u8;
+            buf[2..2 + len].copy_from_slice(domain);
+            buf[(2 + len)..(4 + len)].copy_from_slice(&port.to_be_bytes());
+            4 + len
+        }
+    }
+}
+
+/// Checks the first four bytes of a reply.
+pub(crate) fn check_reply(buf: &[u8]) -> Result<()> {
+    if buf[0] != 0x05 {
+        Err(Error::InvalidResponseVersion {
+            expected: 0x05,
+            found: buf[0],
+        })?
+    }
+    if buf[2] != 0x00 {
+        Err(Error::InvalidReservedByte(buf[2]))?
+    }
+    match buf[1] {
+        0x00 => Ok(()), // succeeded
+        0x01 => Err(Error::GeneralSocksServerFailure),
+        0x02 => Err(Error::ConnectionNotAllowedByRuleset),
+        0x03 => Err(Error::NetworkUnreachable),
+        0x04 => Err(Error::HostUnreachable),
+        0x05 => Err(Error::ConnectionRefused),
+        0x06 => Err(Error::TtlExpired),
+        0x07 => Err(Error::CommandNotSupported),
+        0x08 => Err(Error::AddressTypeNotSupported),
+        code => Err(Error::UnknownReplyCode(code)),
+    }
+}
+
+/// Checks the parsed address of a reply and that nothing follows the reply.
+fn check_strict_reply<S: AsyncRead>(tcp: &mut S, target: &TargetAddr) -> Result<()> {
+    if let TargetAddr::Domain(domain, _) = target {
+        if domain.is_empty() {
+            Err(Error::InvalidTargetAddress("empty domain in the reply"))?
+        }
+    }
+    // Not every transport can peek, and a byte read here is discarded along with the
+    // failed negotiation anyway.
+    match tcp.poll_read(&mut [0; 1])? {
+        Async::Ready(n) if n > 0 => Err(Error::UnexpectedDataAfterReply),
+        // Nothing pending, or the connection is already closed.
+        _ => Ok(()),
+    }
+}
+
+/// Returns the length of the address at the start of `buf`, including the address type
+/// and the port, if `buf` is long enough to contain it.
+#[cfg(feature = "udp")]
+pub(crate) fn address_len(buf: &[u8]) -> Result<Option<usize>> {
+    let len = match buf.first() {
+        None => return Ok(None),
+        Some(0x01) => 7,
+        Some(0x04) => 19,
+        Some(0x03) => match buf.get(1) {
+            Some(len) => *len as usize + 4,
+            None => return Ok(None),
+        },
+        Some(atyp) => Err(Error::UnknownAddressType(*atyp))?,
+    };
+    Ok(if buf.len() >= len { Some(len) } else { None })
+}
+
+/// Parses the address of a reply, starting at the address type.
+pub(crate) fn parse_address(buf: &[u8]) -> Result<TargetAddr<'static>> {
+    let len = buf.len();
+    match buf[0] {
+        // IPv4
+        0x01 => {
+            let mut ip = [0; 4];
+            ip[..].copy_from_slice(&buf[1..5]);
+            let ip = Ipv4Addr::from(ip);
+            let port = u16::from_be_bytes([buf[5], buf[6]]);
+            (ip, port).into_target_addr()
+        }
+        // IPv6
+        0x04 => {
+            let mut ip = [0; 16];
+            ip[..].copy_from_slice(&buf[1..17]);
+            let ip = Ipv6Addr::from(ip);
+            let port = u16::from_be_bytes([buf[17], buf[18]]);
+            (ip, port).into_target_addr()
+        }
+        // Domain
+        0x03 => {
+            let domain_bytes = buf[2..(len - 2)].to_vec();
+            let domain = String::from_utf8(domain_bytes)
+                .map_err(|_| Error::InvalidTargetAddress("not a valid UTF-8 string"))?;
+            let port = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+            Ok(TargetAddr::Domain(domain.into(), port))
+        }
+        atyp => Err(Error::UnknownAddressType(atyp)),
+    }
 }
 
 /// A SOCKS5 BIND client.
@@ -465,40 +1699,130 @@ impl Socks5Listener {
         P: ToProxyAddrs,
         T: IntoTargetAddr<'t>,
     {
-        Ok(BindFuture(ConnectFuture::new(
-            Authentication::Password { username, password },
+        Self::bind_with_password_bytes(proxy, target, username.as_bytes(), password.as_bytes())
+    }
+
+    /// Initiates a BIND request to the specified proxy using given username
+    /// and password as raw bytes.
+    ///
+    /// The proxy will filter incoming connections based on the value of
+    /// `target`.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn bind_with_password_bytes<'a, 't, P, T>(
+        proxy: P,
+        target: T,
+        username: &'a [u8],
+        password: &'a [u8],
+    ) -> Result<BindFuture<'a, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Ok(BindFuture(Socks5Stream::connect_raw(
+            proxy,
+            target,
+            Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
             Command::Bind,
-            proxy.to_proxy_addrs(),
-            target.into_target_addr()?,
-        )))
+        )?))
+    }
+
+    /// Initiates a BIND request to the specified proxy using a custom authentication method.
+    ///
+    /// The proxy will filter incoming connections based on the value of
+    /// `target`.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn bind_with_auth<'t, P, T, A>(
+        proxy: P,
+        target: T,
+        method: A,
+    ) -> Result<BindFuture<'static, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+        A: AuthMethod + 'static,
+    {
+        Ok(BindFuture(Socks5Stream::connect_raw(
+            proxy,
+            target,
+            Authentication::Custom(Arc::new(method)),
+            Command::Bind,
+        )?))
     }
 
     /// Returns the address of the proxy-side TCP listener.
     ///
     /// This should be forwarded to the remote process, which should open a
-    /// connection to it.
+    /// connection to it. The proxy server may report the address as a domain name.
     pub fn bind_addr(&self) -> TargetAddr {
         self.inner.target_addr()
     }
 
+    /// Sets the TCP keepalive idle time of the control connection.
+    ///
+    /// The second reply of a BIND request may take a long time to arrive. Enabling keepalive
+    /// with a short idle time prevents NATs and firewalls from dropping the idle control
+    /// connection in the meantime.
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> io::Result<()> {
+        self.inner.tcp.set_keepalive(keepalive)
+    }
+
+    /// Returns the TCP keepalive idle time of the control connection.
+    pub fn keepalive(&self) -> io::Result<Option<Duration>> {
+        self.inner.tcp.keepalive()
+    }
+
+    /// Creates a `Socks5Listener` from a control connection which has received the first
+    /// reply of a BIND request and the information about the negotiation.
+    pub fn from_parts(tcp: TcpStream, info: HandshakeInfo) -> Self {
+        Socks5Listener {
+            inner: Socks5Stream::from_parts(tcp, info),
+        }
+    }
+
+    /// Consumes the `Socks5Listener`, returning the control connection and the information
+    /// about the negotiation.
+    ///
+    /// In the returned `HandshakeInfo`, `requested_target` is the expected peer sent in the
+    /// BIND request and `target` is the address of the proxy-side TCP listener.
+    pub fn into_parts(self) -> (TcpStream, HandshakeInfo) {
+        self.inner.into_parts()
+    }
+
     /// Consumes this listener, returning a `Future` which resolves to the `Socks5Stream`
     /// connected to the target server through the proxy.
     ///
     /// The value of `bind_addr` should be forwarded to the remote process
-    /// before this method is called.
-    pub fn accept(self) -> impl Future<Item = Socks5Stream, Error = Error> {
-        let mut conn_fut = ConnectFuture {
-            auth: Authentication::None,
-            command: Command::Bind,
-            proxy: stream::empty(),
-            target: self.inner.target,
-            state: ConnectState::RequestSent(Some(self.inner.tcp)),
-            buf: [0; 513],
-            ptr: 0,
-            len: 0,
-        };
-        conn_fut.prepare_recv_reply();
-        conn_fut
+    /// before this method is called. The future resolves when the proxy server sends the
+    /// second reply, and `target_addr` of the resolved stream returns the address of the
+    /// peer which connected to the proxy-side listener.
+    pub fn accept(self) -> AcceptFuture {
+        let (tcp, info) = self.inner.into_parts();
+        let mut negotiation =
+            Negotiation::new(Authentication::None, Command::Bind, info.requested_target);
+        negotiation.negotiated_auth = info.auth;
+        negotiation.wait_for_reply(tcp, info.proxy_addr);
+        AcceptFuture(negotiation)
+    }
+}
+
+/// A `Future` which resolves to the `Socks5Stream` accepted by a `Socks5Listener`.
+pub struct AcceptFuture(Negotiation<'static, 'static, TcpStream>);
+
+impl Future for AcceptFuture {
+    type Item = Socks5Stream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
     }
 }
 
@@ -523,13 +1847,87 @@ where
     }
 }
 
-impl Read for Socks5Stream {
+/// The maximum number of bytes discarded by `Close` before the socket is dropped.
+const CLOSE_DRAIN_LIMIT: usize = 64 * 1024;
+
+/// How long `Close` waits for the other end to close the connection by default.
+const CLOSE_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A `Future` which closes a `Socks5Stream`.
+///
+/// This `struct` is created by the `close` method on `Socks5Stream`.
+pub struct Close {
+    stream: Option<Socks5Stream>,
+    state: CloseState,
+    drained: usize,
+    drain_timeout: Duration,
+    deadline: Option<Delay>,
+}
+
+enum CloseState {
+    Flush,
+    Shutdown,
+    Drain,
+}
+
+impl Close {
+    /// Sets how long to wait for the other end to close the connection after the write half
+    /// is shut down. The default is 5 seconds.
+    ///
+    /// The socket is dropped when the time is up, so a peer which never closes its end
+    /// cannot hold the future forever. The deadline needs the timer of a Tokio runtime;
+    /// without one, the socket is dropped right after the shutdown.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+}
+
+impl Future for Close {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        let stream = self.stream.as_mut().expect("polled after completion");
+        loop {
+            match self.state {
+                CloseState::Flush => {
+                    try_ready!(stream.poll_flush());
+                    self.state = CloseState::Shutdown;
+                }
+                CloseState::Shutdown => {
+                    try_ready!(AsyncWrite::shutdown(stream));
+                    // `AsyncWrite::shutdown` of `tokio_tcp::TcpStream` does not send a FIN.
+                    stream.tcp.shutdown(Shutdown::Write)?;
+                    self.deadline = Some(Delay::new(Instant::now() + self.drain_timeout));
+                    self.state = CloseState::Drain;
+                }
+                CloseState::Drain => {
+                    let deadline = self.deadline.as_mut().unwrap();
+                    // A timer error means there is no timer to wait with.
+                    if let Ok(Async::NotReady) = deadline.poll() {
+                        let mut buf = [0; 4096];
+                        let n = try_ready!(stream.poll_read(&mut buf));
+                        self.drained += n;
+                        if n > 0 && self.drained < CLOSE_DRAIN_LIMIT {
+                            continue;
+                        }
+                    }
+                    self.stream = None;
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+    }
+}
+
+impl<S: Read> Read for Socks5Stream<S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.tcp.read(buf)
     }
 }
 
-impl Write for Socks5Stream {
+impl<S: Write> Write for Socks5Stream<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.tcp.write(buf)
     }
@@ -538,17 +1936,17 @@ impl Write for Socks5Stream {
     }
 }
 
-impl AsyncRead for Socks5Stream {
+impl<S: AsyncRead> AsyncRead for Socks5Stream<S> {
     unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
         self.tcp.prepare_uninitialized_buffer(buf)
     }
 
     fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
-        self.tcp.read_buf(buf)
+        AsyncRead::read_buf(&mut self.tcp, buf)
     }
 }
 
-impl AsyncWrite for Socks5Stream {
+impl<S: AsyncWrite> AsyncWrite for Socks5Stream<S> {
     fn shutdown(&mut self) -> Poll<(), io::Error> {
         AsyncWrite::shutdown(&mut self.tcp)
     }
@@ -593,3 +1991,716 @@ impl<'a> AsyncWrite for &'a Socks5Stream {
         AsyncWrite::write_buf(&mut &self.tcp, buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Spawns a proxy server which answers each message of the client with the next given
+    /// reply, then waits for the client to close the connection.
+    fn scripted_proxy(replies: &'static [&'static [u8]]) -> SocketAddr {
+        recording_proxy(replies).0
+    }
+
+    /// Spawns a proxy server like `scripted_proxy`, which also sends each message of the
+    /// client to the returned receiver.
+    fn recording_proxy(
+        replies: &'static [&'static [u8]],
+    ) -> (SocketAddr, std::sync::mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            for reply in replies {
+                match tcp.read(&mut buf).unwrap_or(0) {
+                    0 => return,
+                    n => {
+                        let _ = tx.send(buf[..n].to_vec());
+                    }
+                }
+                tcp.write_all(reply).unwrap();
+            }
+            while tcp.read(&mut buf).unwrap_or(0) > 0 {}
+        });
+        (addr, rx)
+    }
+
+    /// Spawns a proxy server which reads the greeting, sends `reply` and closes the
+    /// connection.
+    fn closing_proxy(reply: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = tcp.read(&mut buf);
+            let _ = tcp.write_all(reply);
+        });
+        addr
+    }
+
+    fn connect(proxy: SocketAddr) -> Result<Socks5Stream> {
+        connect_to(proxy, "www.example.com:80")
+    }
+
+    fn connect_to(proxy: SocketAddr, target: &str) -> Result<Socks5Stream> {
+        let conn = Socks5Stream::connect(proxy, target)?;
+        tokio::runtime::current_thread::block_on_all(conn)
+    }
+
+    #[test]
+    fn sets_traffic_class() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let tcp = std::net::TcpStream::connect(addr)?;
+        let tcp = TcpStream::from_std(tcp, &Default::default())?;
+        let info = HandshakeInfo {
+            proxy_addr: addr,
+            requested_target: TargetAddr::Ip(addr),
+            target: TargetAddr::Ip(addr),
+            auth: NegotiatedAuth::None,
+        };
+        let stream = Socks5Stream::from_parts(tcp, info);
+        stream.set_traffic_class(TrafficClass::Bulk)?;
+        assert_eq!(SockRef::from(&stream.tcp).tos()?, 8 << 2);
+        stream.set_traffic_class(TrafficClass::Interactive)?;
+        assert_eq!(SockRef::from(&stream.tcp).tos()?, 18 << 2);
+        Ok(())
+    }
+
+    #[test]
+    fn socket_options_pass_through_deref() -> Result<()> {
+        let stream = connect(scripted_proxy(&[
+            b"\x05\x00",
+            b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+        ]))?;
+        stream.set_nodelay(true)?;
+        assert!(stream.nodelay()?);
+        stream.set_ttl(42)?;
+        assert_eq!(stream.ttl()?, 42);
+        stream.set_linger(Some(Duration::from_secs(1)))?;
+        assert_eq!(stream.linger()?, Some(Duration::from_secs(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn classifies_bnd_addr() {
+        let requested: TargetAddr = "10.0.0.1:80".into_target_addr().unwrap();
+        let mut info = HandshakeInfo {
+            proxy_addr: SocketAddr::from(([127, 0, 0, 1], 1080)),
+            requested_target: requested.to_owned(),
+            target: "0.0.0.0:0".into_target_addr().unwrap(),
+            auth: NegotiatedAuth::None,
+        };
+        assert_eq!(info.bnd_addr(), BndAddr::Unspecified);
+        info.target = requested.to_owned();
+        assert_eq!(info.bnd_addr(), BndAddr::AsRequested(&requested));
+        info.target = "192.168.0.1:4000".into_target_addr().unwrap();
+        assert_eq!(info.bnd_addr(), BndAddr::ProxyChosen(&info.target));
+    }
+
+    struct TokenAuth;
+
+    impl AuthMethod for TokenAuth {
+        fn id(&self) -> u8 {
+            0x80
+        }
+
+        fn authenticate(&self, tcp: TcpStream) -> AuthFuture {
+            Box::new(
+                tokio_io::io::write_all(tcp, b"token")
+                    .and_then(|(tcp, _)| tokio_io::io::read_exact(tcp, [0; 1]))
+                    .map_err(Error::from)
+                    .and_then(|(tcp, reply)| match reply {
+                        [0x00] => Ok(tcp),
+                        [code] => Err(Error::PasswordAuthFailure(code)),
+                    }),
+            )
+        }
+    }
+
+    #[test]
+    fn custom_auth_method_is_negotiated() -> Result<()> {
+        let proxy = scripted_proxy(&[
+            b"\x05\x80",
+            b"\x00",
+            b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+        ]);
+        let conn = Socks5Stream::connect_with_auth(proxy, "www.example.com:80", TokenAuth)?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(stream.negotiated_auth(), NegotiatedAuth::Custom(0x80));
+
+        let proxy = scripted_proxy(&[b"\x05\x80", b"\x01"]);
+        let conn = Socks5Stream::connect_with_auth(proxy, "www.example.com:80", TokenAuth)?;
+        match tokio::runtime::current_thread::block_on_all(conn) {
+            Err(Error::PasswordAuthFailure(0x01)) => Ok(()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reserved_custom_auth_method_id_is_rejected() {
+        struct Reserved(u8);
+
+        impl AuthMethod for Reserved {
+            fn id(&self) -> u8 {
+                self.0
+            }
+
+            fn authenticate(&self, tcp: TcpStream) -> AuthFuture {
+                Box::new(futures::future::ok(tcp))
+            }
+        }
+
+        let proxy = SocketAddr::from(([127, 0, 0, 1], 1080));
+        for &id in &[0x00, 0x02, 0xff] {
+            match Socks5Stream::connect_with_auth(proxy, "www.example.com:80", Reserved(id)) {
+                Err(Error::InvalidAuthValues(_)) => {}
+                _ => panic!("reserved method ID {:#04x} is accepted", id),
+            }
+        }
+    }
+
+    #[test]
+    fn offered_methods_are_selected_by_server() -> Result<()> {
+        let methods = || {
+            vec![
+                Method::Custom(Arc::new(TokenAuth)),
+                Method::Password {
+                    username: b"user",
+                    password: b"pass",
+                },
+            ]
+        };
+        let mut buf = [0; 513];
+        let auth = Authentication::Multiple(methods().into_iter().map(Into::into).collect());
+        let len = encode_method_selection(&auth, &mut buf);
+        assert_eq!(&buf[..len], b"\x05\x02\x80\x02");
+
+        let proxy = scripted_proxy(&[
+            b"\x05\x02",
+            b"\x01\x00",
+            b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+        ]);
+        let conn = Socks5Stream::connect_with_methods(proxy, "www.example.com:80", methods())?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(stream.negotiated_auth(), NegotiatedAuth::Password);
+
+        let proxy = scripted_proxy(&[
+            b"\x05\x80",
+            b"\x00",
+            b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+        ]);
+        let conn = Socks5Stream::connect_with_methods(proxy, "www.example.com:80", methods())?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(stream.negotiated_auth(), NegotiatedAuth::Custom(0x80));
+
+        // No authentication was not offered, so the proxy server may not select it.
+        let proxy = scripted_proxy(&[b"\x05\x00"]);
+        let conn = Socks5Stream::connect_with_methods(proxy, "www.example.com:80", methods())?;
+        match tokio::runtime::current_thread::block_on_all(conn) {
+            Err(Error::UnknownAuthMethod) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let proxy = SocketAddr::from(([127, 0, 0, 1], 1080));
+        for methods in [vec![], vec![Method::None, Method::None]] {
+            match Socks5Stream::connect_with_methods(proxy, "www.example.com:80", methods) {
+                Err(Error::InvalidAuthValues(_)) => {}
+                _ => panic!("invalid methods are accepted"),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tor")]
+    #[test]
+    fn tor_resolve_returns_resolved_ip() -> Result<()> {
+        let proxy = scripted_proxy(&[b"\x05\x00", b"\x05\x00\x00\x01\x0a\0\0\x01\0\0"]);
+        let resolve = Socks5Stream::tor_resolve(proxy, "www.example.com")?;
+        let ip = tokio::runtime::current_thread::block_on_all(resolve)?;
+        assert_eq!(ip, std::net::IpAddr::from([10, 0, 0, 1]));
+        Ok(())
+    }
+
+    #[cfg(feature = "tor")]
+    #[test]
+    fn tor_resolve_ptr_returns_hostname() -> Result<()> {
+        let proxy = scripted_proxy(&[b"\x05\x00", b"\x05\x00\x00\x03\x0fwww.example.com\0\0"]);
+        let resolve = Socks5Stream::tor_resolve_ptr(proxy, [10, 0, 0, 1].into());
+        let hostname = tokio::runtime::current_thread::block_on_all(resolve)?;
+        assert_eq!(hostname, "www.example.com");
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_request_keeps_reply_code() {
+        let rejections: &[(&'static [&'static [u8]], u8)] = &[
+            (&[b"\x05\x00", b"\x05\x02\x00\x01\0\0\0\0\0\0"], 0x02),
+            (&[b"\x05\x00", b"\x05\x04\x00\x01\0\0\0\0\0\0"], 0x04),
+            (&[b"\x05\x00", b"\x05\x42\x00\x01\0\0\0\0\0\0"], 0x42),
+        ];
+        for (replies, code) in rejections {
+            match connect(scripted_proxy(replies)) {
+                Err(e) => assert_eq!(e.reply_code(), Some(*code)),
+                Ok(_) => panic!("request should be rejected"),
+            }
+        }
+        assert_eq!(Error::UnknownAuthMethod.reply_code(), None);
+    }
+
+    #[test]
+    fn connect_reply_may_contain_domain() -> Result<()> {
+        let stream = connect(scripted_proxy(&[
+            b"\x05\x00",
+            b"\x05\x00\x00\x03\x0bexample.com\x01\xbb",
+        ]))?;
+        assert_eq!(
+            stream.target_addr(),
+            TargetAddr::Domain("example.com".into(), 443)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reply_domain_longer_than_limit_is_rejected() -> Result<()> {
+        let proxy = scripted_proxy(&[b"\x05\x00", b"\x05\x00\x00\x03\x0bexample.com\x01\xbb"]);
+        let conn =
+            Socks5Stream::connect(proxy, "www.example.com:80")?.with_max_reply_domain_len(10);
+        match tokio::runtime::current_thread::block_on_all(conn) {
+            Err(Error::ReplyDomainTooLong(11)) => Ok(()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reply_domain_longer_than_limit_is_rejected_through_socket() -> Result<()> {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+        let socket = runtime.block_on(TcpStream::connect(&scripted_proxy(&[
+            b"\x05\x00",
+            b"\x05\x00\x00\x03\x0bexample.com\x01\xbb",
+        ])))?;
+        let conn = Socks5Stream::connect_with_socket(socket, "www.example.com:80")?
+            .with_max_reply_domain_len(10);
+        match runtime.block_on(conn) {
+            Err(Error::ReplyDomainTooLong(11)) => Ok(()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn connects_through_an_established_tunnel() -> Result<()> {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+        let conn = Socks5Stream::connect(
+            scripted_proxy(&[
+                b"\x05\x00",
+                b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+                b"\x05\x02",
+                b"\x01\x00",
+                b"\x05\x00\x00\x03\x0bexample.com\x01\xbb",
+            ]),
+            "www.example.com:80",
+        )?;
+        let outer = runtime.block_on(conn)?;
+        let conn = Socks5Stream::connect_with_socket_and_password(
+            outer,
+            "www.example.com:443",
+            "mylogin",
+            "mypassword",
+        )?;
+        let inner = runtime.block_on(conn)?;
+        assert_eq!(inner.negotiated_auth(), NegotiatedAuth::Password);
+        assert_eq!(
+            inner.target_addr(),
+            TargetAddr::Domain("example.com".into(), 443)
+        );
+        assert_eq!(
+            inner.into_inner().target_addr(),
+            TargetAddr::Ip(SocketAddr::from(([127, 0, 0, 1], 1080)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn socket_connect_supports_custom_auth_and_progress() -> Result<()> {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+        let socket = runtime.block_on(TcpStream::connect(&scripted_proxy(&[
+            b"\x05\x80",
+            b"\x00",
+            b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+        ])))?;
+        let mut conn =
+            Socks5Stream::connect_with_socket_and_auth(socket, "www.example.com:80", TokenAuth)?
+                .with_strict_reply();
+        let progress = conn.progress();
+        let stream = runtime.block_on(conn)?;
+        assert_eq!(stream.negotiated_auth(), NegotiatedAuth::Custom(0x80));
+        let steps = runtime.block_on(progress.collect()).unwrap();
+        assert_eq!(
+            steps,
+            vec![
+                ConnectProgress::Negotiating,
+                ConnectProgress::Authenticating,
+                ConnectProgress::Requesting,
+                ConnectProgress::Established,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn layer_supports_password_auth() -> Result<()> {
+        let proxy = scripted_proxy(&[
+            b"\x05\x02",
+            b"\x01\x00",
+            b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+        ]);
+        let conn = Socks5Stream::connect_layered_with_password(
+            proxy,
+            "www.example.com:80",
+            |tcp| tcp,
+            "mylogin",
+            "mypassword",
+        )?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(stream.negotiated_auth(), NegotiatedAuth::Password);
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_only_runs_on_any_transport() -> Result<()> {
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+        let conn = Socks5Stream::connect(
+            scripted_proxy(&[
+                b"\x05\x00",
+                b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+                b"\x05\x02",
+                b"\x01\x00",
+                b"\x05\x00\x00\x01\x7f\0\0\x01\x01\xbb",
+            ]),
+            "www.example.com:80",
+        )?;
+        let outer = runtime.block_on(conn)?;
+        let auth = Method::Password {
+            username: b"mylogin",
+            password: b"mypassword",
+        };
+        let conn = handshake_only(outer, "www.example.com:443", auth)?;
+        let (outer, info) = runtime.block_on(conn)?;
+        assert_eq!(info.auth, NegotiatedAuth::Password);
+        assert_eq!(info.proxy_addr, SocketAddr::from(([0, 0, 0, 0], 0)));
+        assert_eq!(
+            outer.target_addr(),
+            TargetAddr::Ip(SocketAddr::from(([127, 0, 0, 1], 1080)))
+        );
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "unix"))]
+    #[test]
+    fn connects_through_unix_socket() -> Result<()> {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("tokio-socks-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        thread::spawn(move || {
+            let (mut unix, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let replies: [&[u8]; 2] = [b"\x05\x00", b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38"];
+            for reply in &replies {
+                if unix.read(&mut buf).unwrap_or(0) == 0 {
+                    return;
+                }
+                unix.write_all(reply).unwrap();
+            }
+        });
+        let conn = Socks5Stream::connect_unix(&path, "www.example.com:80")?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn);
+        std::fs::remove_file(&path)?;
+        assert_eq!(
+            stream?.target_addr(),
+            TargetAddr::Ip(SocketAddr::from(([127, 0, 0, 1], 1080)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn layer_wraps_proxy_connection() -> Result<()> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let proxy = scripted_proxy(&[b"\x05\x00", b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38"]);
+        let wrapped = Arc::new(AtomicBool::new(false));
+        let layer = {
+            let wrapped = wrapped.clone();
+            move |tcp| {
+                wrapped.store(true, Ordering::SeqCst);
+                tcp
+            }
+        };
+        let conn = Socks5Stream::connect_layered(proxy, "www.example.com:80", layer)?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert!(wrapped.load(Ordering::SeqCst));
+        assert_eq!(stream.proxy_addr(), proxy);
+        Ok(())
+    }
+
+    #[test]
+    fn strict_reply_rejects_trailing_data() -> Result<()> {
+        let reply: &'static [&'static [u8]] = &[
+            b"\x05\x00",
+            b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38HTTP/1.1 200 OK\r\n",
+        ];
+        let conn = Socks5Stream::connect(scripted_proxy(reply), "www.example.com:443")?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(
+            stream.target_addr(),
+            TargetAddr::Ip(SocketAddr::from(([127, 0, 0, 1], 1080)))
+        );
+
+        let conn = Socks5Stream::connect(scripted_proxy(reply), "www.example.com:443")?;
+        match tokio::runtime::current_thread::block_on_all(conn.with_strict_reply()) {
+            Err(Error::UnexpectedDataAfterReply) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let conn = Socks5Stream::connect(
+            scripted_proxy(&[b"\x05\x00", b"\x05\x00\x00\x03\x00\x01\xbb"]),
+            "www.example.com:443",
+        )?;
+        match tokio::runtime::current_thread::block_on_all(conn.with_strict_reply()) {
+            Err(Error::InvalidTargetAddress(_)) => Ok(()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn bind_replies_may_contain_domains() -> Result<()> {
+        let proxy = scripted_proxy(&[
+            b"\x05\x00",
+            b"\x05\x00\x00\x03\x11relay.example.com\x0f\xa0\
+              \x05\x00\x00\x03\x10peer.example.com\x1f\x40",
+        ]);
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+        let bind = Socks5Listener::bind(proxy, "peer.example.com:0")?;
+        let listener = runtime.block_on(bind)?;
+        assert_eq!(
+            listener.bind_addr(),
+            TargetAddr::Domain("relay.example.com".into(), 4000)
+        );
+        let stream = runtime.block_on(listener.accept())?;
+        assert_eq!(
+            stream.target_addr(),
+            TargetAddr::Domain("peer.example.com".into(), 8000)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reports_connect_progress() -> Result<()> {
+        let proxy = scripted_proxy(&[
+            b"\x05\x02",
+            b"\x01\x00",
+            b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+        ]);
+        let mut conn =
+            Socks5Stream::connect_with_password(proxy, "www.example.com:80", "user", "pass")?;
+        let progress = conn.progress();
+        tokio::runtime::current_thread::block_on_all(conn)?;
+        let steps = progress.collect().wait().unwrap();
+        assert_eq!(
+            steps,
+            vec![
+                ConnectProgress::Resolving,
+                ConnectProgress::Connecting(proxy),
+                ConnectProgress::Negotiating,
+                ConnectProgress::Authenticating,
+                ConnectProgress::Requesting,
+                ConnectProgress::Established,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn password_auth_is_skipped_if_server_chooses_no_auth() -> Result<()> {
+        let proxy = scripted_proxy(&[b"\x05\x00", b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38"]);
+        let conn =
+            Socks5Stream::connect_with_password(proxy, "www.example.com:80", "user", "pass")?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(stream.negotiated_auth(), NegotiatedAuth::None);
+        assert_eq!(
+            stream.target_addr(),
+            TargetAddr::Ip(SocketAddr::from(([127, 0, 0, 1], 1080)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn close_gives_up_on_silent_peer() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        thread::spawn(move || {
+            // Keep the connection open without ever closing it.
+            let (_tcp, _) = listener.accept().unwrap();
+            let _ = rx.recv();
+        });
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+        let tcp = runtime.block_on(TcpStream::connect(&addr))?;
+        let info = HandshakeInfo {
+            proxy_addr: addr,
+            requested_target: TargetAddr::Ip(addr),
+            target: TargetAddr::Ip(addr),
+            auth: NegotiatedAuth::None,
+        };
+        let start = Instant::now();
+        let close = Socks5Stream::from_parts(tcp, info)
+            .close()
+            .with_drain_timeout(Duration::from_millis(100));
+        runtime.block_on(close)?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(tx);
+        Ok(())
+    }
+
+    #[test]
+    fn closed_connection_fails_instead_of_spinning() {
+        for reply in &[&b""[..], &b"\x05"[..]] {
+            match connect(closing_proxy(reply)) {
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_version_carries_received_byte() {
+        let proxy = scripted_proxy(&[b"\x04\x00"]);
+        match connect(proxy) {
+            Err(Error::InvalidResponseVersion {
+                expected: 0x05,
+                found: 0x04,
+            }) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn http_reply_is_detected() {
+        let proxy = scripted_proxy(&[b"HTTP/1.1 400 Bad Request\r\n\r\n"]);
+        match connect(proxy) {
+            Err(Error::NotASocksProxy {
+                looks_like: DetectedProtocol::Http,
+            }) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn ttl_expired_retries_next_proxy() -> Result<()> {
+        let proxies = [
+            scripted_proxy(&[b"\x05\x00", b"\x05\x06\x00\x01\0\0\0\0\0\0"]),
+            scripted_proxy(&[b"\x05\x00", b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38"]),
+        ];
+        let conn = Socks5Stream::connect(&proxies[..], "www.example.com:80")?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(stream.proxy_addr(), proxies[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn ttl_expired_retry_offers_all_methods_again() -> Result<()> {
+        let first = scripted_proxy(&[b"\x05\x02", b"\x01\x00", b"\x05\x06\x00\x01\0\0\0\0\0\0"]);
+        let (second, greetings) = recording_proxy(&[
+            b"\x05\x80",
+            b"\x00",
+            b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38",
+        ]);
+        let methods = vec![
+            Method::Custom(Arc::new(TokenAuth)),
+            Method::Password {
+                username: b"user",
+                password: b"pass",
+            },
+        ];
+        let proxies = [first, second];
+        let conn = Socks5Stream::connect_with_methods(&proxies[..], "www.example.com:80", methods)?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(stream.proxy_addr(), second);
+        assert_eq!(stream.negotiated_auth(), NegotiatedAuth::Custom(0x80));
+        assert_eq!(greetings.recv().unwrap(), b"\x05\x02\x80\x02");
+        Ok(())
+    }
+
+    #[test]
+    fn ttl_expired_retry_can_be_turned_off() -> Result<()> {
+        let proxies = [
+            scripted_proxy(&[b"\x05\x00", b"\x05\x06\x00\x01\0\0\0\0\0\0"]),
+            scripted_proxy(&[b"\x05\x00", b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38"]),
+        ];
+        let conn =
+            Socks5Stream::connect(&proxies[..], "www.example.com:80")?.retry_on_ttl_expired(false);
+        match tokio::runtime::current_thread::block_on_all(conn) {
+            Err(Error::TtlExpired) => {}
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ttl_expired_without_other_proxy_fails() {
+        let proxy = scripted_proxy(&[b"\x05\x00", b"\x05\x06\x00\x01\0\0\0\0\0\0"]);
+        match connect(proxy) {
+            Err(Error::TtlExpired) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn invalid_reserved_byte_carries_received_byte() {
+        let proxy = scripted_proxy(&[b"\x05\x00", b"\x05\x00\x07\x01\0\0\0\0\0\0"]);
+        match connect(proxy) {
+            Err(Error::InvalidReservedByte(0x07)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn unknown_address_type_carries_received_byte() {
+        let proxy = scripted_proxy(&[b"\x05\x00", b"\x05\x00\x00\x09\0\0\0\0\0\0"]);
+        match connect(proxy) {
+            Err(Error::UnknownAddressType(0x09)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn port_zero_is_rejected_before_connecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        drop(listener);
+        match connect_to(proxy, "www.example.com:0") {
+            Err(Error::InvalidTargetAddress(_)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn port_zero_can_be_allowed() -> Result<()> {
+        let (proxy, requests) =
+            recording_proxy(&[b"\x05\x00", b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38"]);
+        let conn = Socks5Stream::connect(proxy, "www.example.com:0")?.allow_port_zero();
+        tokio::runtime::current_thread::block_on_all(conn)?;
+        requests.recv().unwrap();
+        assert_eq!(
+            requests.recv().unwrap(),
+            b"\x05\x01\x00\x03\x0fwww.example.com\x00\x00"
+        );
+        Ok(())
+    }
+}
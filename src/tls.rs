@@ -0,0 +1,360 @@
+//! SOCKS5 over TLS, as offered by providers which only accept the negotiation inside a TLS
+//! connection to the proxy server.
+//!
+//! The TLS connection is set up with `tokio-rustls` by `connect_tls` if the `rustls`
+//! feature is enabled, or with `tokio-tls` and the trust store of the operating system by
+//! `connect_native_tls` if the `native-tls` feature is enabled. The SOCKS5 negotiation then
+//! runs on the TLS stream like in `Socks5Stream::connect_with_socket`.
+
+use crate::{
+    tcp::{validate_auth, SocketConnectFuture, Socks5Stream},
+    Authentication, Error, IntoTargetAddr, Result, TargetAddr, ToProxyAddrs,
+};
+use futures::{try_ready, Async, Future, Poll, Stream};
+#[cfg(feature = "native-tls")]
+use std::io;
+use std::net::SocketAddr;
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+use tokio_io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "rustls")]
+use tokio_rustls::{
+    rustls::ClientConfig,
+    webpki::{DNSName, DNSNameRef},
+    TlsConnector,
+};
+use tokio_tcp::{ConnectFuture as TokioConnect, TcpStream};
+
+/// A TLS stream to the proxy server set up with `tokio-rustls`.
+#[cfg(feature = "rustls")]
+pub type RustlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+/// A TLS stream to the proxy server set up with `tokio-tls`.
+#[cfg(feature = "native-tls")]
+pub type NativeTlsStream = tokio_tls::TlsStream<TcpStream>;
+
+/// The TLS handshake on the connection to the proxy server.
+type Handshake<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+/// Connects to a target server through a SOCKS5 proxy, over a TLS connection to the proxy
+/// server with `config`. The certificate of the proxy server is verified for `domain`,
+/// which is also sent as SNI.
+///
+/// # Error
+///
+/// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+#[cfg(feature = "rustls")]
+pub fn connect_tls<'t, P, T>(
+    proxy: P,
+    config: Arc<ClientConfig>,
+    domain: DNSNameRef<'_>,
+    target: T,
+) -> Result<TlsConnectFuture<'static, 't, P::Output, RustlsStream>>
+where
+    P: ToProxyAddrs,
+    T: IntoTargetAddr<'t>,
+{
+    let handshake = rustls_handshake(config, domain.to_owned());
+    connect_raw(
+        proxy.to_proxy_addrs(),
+        handshake,
+        target,
+        Authentication::None,
+    )
+}
+
+/// Connects to a target server through a SOCKS5 proxy using given username and password,
+/// over a TLS connection to the proxy server like `connect_tls`.
+///
+/// # Error
+///
+/// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+/// fails if the length of `username` or `password` is not between 1 and 255.
+#[cfg(feature = "rustls")]
+pub fn connect_tls_with_password<'a, 't, P, T>(
+    proxy: P,
+    config: Arc<ClientConfig>,
+    domain: DNSNameRef<'_>,
+    target: T,
+    username: &'a str,
+    password: &'a str,
+) -> Result<TlsConnectFuture<'a, 't, P::Output, RustlsStream>>
+where
+    P: ToProxyAddrs,
+    T: IntoTargetAddr<'t>,
+{
+    let auth = Authentication::Password {
+        username: username.as_bytes().into(),
+        password: password.as_bytes().into(),
+    };
+    let handshake = rustls_handshake(config, domain.to_owned());
+    connect_raw(proxy.to_proxy_addrs(), handshake, target, auth)
+}
+
+#[cfg(feature = "rustls")]
+fn rustls_handshake(
+    config: Arc<ClientConfig>,
+    domain: DNSName,
+) -> impl Fn(TcpStream) -> Handshake<RustlsStream> + Send {
+    let connector = TlsConnector::from(config);
+    move |tcp| Box::new(connector.connect(domain.as_ref(), tcp).map_err(Error::from))
+}
+
+/// Connects to a target server through a SOCKS5 proxy, over a TLS connection to the proxy
+/// server with `connector`, e.g. a `native_tls::TlsConnector` converted with `into()`. The
+/// certificate of the proxy server is verified for `domain`, which is also sent as SNI.
+///
+/// # Error
+///
+/// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+#[cfg(feature = "native-tls")]
+pub fn connect_native_tls<'t, P, T>(
+    proxy: P,
+    connector: tokio_tls::TlsConnector,
+    domain: &str,
+    target: T,
+) -> Result<TlsConnectFuture<'static, 't, P::Output, NativeTlsStream>>
+where
+    P: ToProxyAddrs,
+    T: IntoTargetAddr<'t>,
+{
+    let handshake = native_tls_handshake(connector, domain.to_owned());
+    connect_raw(
+        proxy.to_proxy_addrs(),
+        handshake,
+        target,
+        Authentication::None,
+    )
+}
+
+/// Connects to a target server through a SOCKS5 proxy using given username and password,
+/// over a TLS connection to the proxy server like `connect_native_tls`.
+///
+/// # Error
+///
+/// It propagates the error that occurs in the conversion from `T` to `TargetAddr`, and
+/// fails if the length of `username` or `password` is not between 1 and 255.
+#[cfg(feature = "native-tls")]
+pub fn connect_native_tls_with_password<'a, 't, P, T>(
+    proxy: P,
+    connector: tokio_tls::TlsConnector,
+    domain: &str,
+    target: T,
+    username: &'a str,
+    password: &'a str,
+) -> Result<TlsConnectFuture<'a, 't, P::Output, NativeTlsStream>>
+where
+    P: ToProxyAddrs,
+    T: IntoTargetAddr<'t>,
+{
+    let auth = Authentication::Password {
+        username: username.as_bytes().into(),
+        password: password.as_bytes().into(),
+    };
+    let handshake = native_tls_handshake(connector, domain.to_owned());
+    connect_raw(proxy.to_proxy_addrs(), handshake, target, auth)
+}
+
+#[cfg(feature = "native-tls")]
+fn native_tls_handshake(
+    connector: tokio_tls::TlsConnector,
+    domain: String,
+) -> impl Fn(TcpStream) -> Handshake<NativeTlsStream> + Send {
+    move |tcp| Box::new(connector.connect(&domain, tcp).map_err(tls_error))
+}
+
+/// Converts an error of the TLS handshake with `tokio-tls`.
+// `io::Error::other`, which the lint suggests, needs a newer compiler than the crate supports.
+#[cfg(feature = "native-tls")]
+#[allow(clippy::io_other_error)]
+fn tls_error<E>(e: E) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    io::Error::new(io::ErrorKind::Other, e).into()
+}
+
+fn connect_raw<'a, 't, S, T, F, H>(
+    proxy: S,
+    handshake: F,
+    target: H,
+    auth: Authentication<'a, T>,
+) -> Result<TlsConnectFuture<'a, 't, S, T>>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+    F: Fn(TcpStream) -> Handshake<T> + Send + 'static,
+    H: IntoTargetAddr<'t>,
+{
+    validate_auth(&auth)?;
+    Ok(TlsConnectFuture {
+        proxy,
+        handshake: Box::new(handshake),
+        auth: Some(auth),
+        target: Some(target.into_target_addr()?),
+        state: State::Resolving,
+    })
+}
+
+/// A `Future` which resolves to a socket to the target server through a proxy, over a TLS
+/// stream `T` to the proxy server.
+///
+/// The proxy addresses are tried in order until a TCP connection is established. The
+/// future fails if the TLS handshake or the SOCKS5 negotiation on it fails.
+pub struct TlsConnectFuture<'a, 't, S, T>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    proxy: S,
+    handshake: Box<dyn Fn(TcpStream) -> Handshake<T> + Send>,
+    auth: Option<Authentication<'a, T>>,
+    target: Option<TargetAddr<'t>>,
+    state: State<'a, 't, T>,
+}
+
+enum State<'a, 't, T> {
+    Resolving,
+    Connecting(TokioConnect),
+    Handshaking(SocketAddr, Handshake<T>),
+    Negotiating(Box<SocketConnectFuture<'a, 't, T>>),
+}
+
+impl<'a, 't, S, T> Future for TlsConnectFuture<'a, 't, S, T>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+    T: AsyncRead + AsyncWrite,
+{
+    type Item = Socks5Stream<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Socks5Stream<T>, Error> {
+        loop {
+            self.state = match &mut self.state {
+                State::Resolving => match try_ready!(self.proxy.poll()) {
+                    Some(addr) => State::Connecting(TcpStream::connect(&addr)),
+                    None => Err(Error::ProxyServerUnreachable)?,
+                },
+                State::Connecting(conn) => match conn.poll() {
+                    Ok(Async::Ready(tcp)) => {
+                        let addr = tcp.peer_addr()?;
+                        State::Handshaking(addr, (self.handshake)(tcp))
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(_) => State::Resolving,
+                },
+                State::Handshaking(addr, handshake) => {
+                    let tls = try_ready!(handshake.poll());
+                    let auth = self.auth.take().unwrap();
+                    let target = self.target.take().unwrap();
+                    let negotiation = SocketConnectFuture::new(tls, auth, target);
+                    State::Negotiating(Box::new(negotiation.with_proxy_addr(*addr)))
+                }
+                State::Negotiating(negotiation) => return negotiation.poll(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_io::io::{read_exact, write_all};
+    use tokio_tcp::TcpListener;
+
+    // A CA and a certificate for `localhost` issued by it, made with `openssl req` and
+    // `openssl x509 -req`.
+    const CA: &[u8] = include_bytes!("../tests/tls/ca.pem");
+    const CERT: &[u8] = include_bytes!("../tests/tls/localhost.pem");
+    const KEY: &[u8] = include_bytes!("../tests/tls/localhost.key");
+
+    /// Accepts one TLS connection, runs the proxy side of a negotiation without
+    /// authentication of a request of `request_len` bytes, and returns the request.
+    fn tls_proxy<S, F>(
+        listener: TcpListener,
+        accept: F,
+        request_len: usize,
+    ) -> impl Future<Item = Vec<u8>, Error = Error>
+    where
+        S: AsyncRead + AsyncWrite,
+        F: FnOnce(TcpStream) -> Handshake<S>,
+    {
+        listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| Error::from(e))
+            .and_then(|(tcp, _)| accept(tcp.unwrap()))
+            .and_then(|tls| read_exact(tls, [0; 3]).map_err(Error::from))
+            .and_then(|(tls, _)| write_all(tls, b"\x05\x00").map_err(Error::from))
+            .and_then(move |(tls, _)| read_exact(tls, vec![0; request_len]).map_err(Error::from))
+            .and_then(|(tls, request)| {
+                write_all(tls, b"\x05\x00\x00\x01\x7f\0\0\x01\x04\x38")
+                    .map(|_| request)
+                    .map_err(Error::from)
+            })
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn negotiates_over_rustls() -> Result<()> {
+        use tokio_rustls::{
+            rustls::{internal::pemfile, NoClientAuth, ServerConfig},
+            TlsAcceptor,
+        };
+
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        let certs = pemfile::certs(&mut &CERT[..]).unwrap();
+        let key = pemfile::pkcs8_private_keys(&mut &KEY[..])
+            .unwrap()
+            .remove(0);
+        server_config.set_single_cert(certs, key).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let mut client_config = ClientConfig::new();
+        client_config.root_store.add_pem_file(&mut &CA[..]).unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())?;
+        let proxy = listener.local_addr()?;
+        let server = tls_proxy(
+            listener,
+            move |tcp| Box::new(acceptor.accept(tcp).map_err(Error::from)),
+            18,
+        );
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let conn = connect_tls(proxy, Arc::new(client_config), domain, "example.com:443")?;
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+        let (stream, request) = runtime.block_on(conn.join(server))?;
+        assert_eq!(&request[..], b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+        assert_eq!(stream.proxy_addr(), proxy);
+        assert_eq!(
+            stream.target_addr(),
+            TargetAddr::Ip(([127, 0, 0, 1], 1080).into())
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "native-tls")]
+    #[test]
+    fn negotiates_over_native_tls() -> Result<()> {
+        use native_tls::{Certificate, Identity};
+
+        let identity = Identity::from_pkcs8(CERT, KEY).unwrap();
+        let acceptor =
+            tokio_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(Certificate::from_pem(CA).unwrap())
+            .build()
+            .unwrap();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())?;
+        let proxy = listener.local_addr()?;
+        let server = tls_proxy(
+            listener,
+            move |tcp| Box::new(acceptor.accept(tcp).map_err(tls_error)),
+            18,
+        );
+        let conn = connect_native_tls(proxy, connector.into(), "localhost", "example.com:443")?;
+        let mut runtime = tokio::runtime::current_thread::Runtime::new()?;
+        let (stream, request) = runtime.block_on(conn.join(server))?;
+        assert_eq!(&request[..], b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+        assert_eq!(stream.proxy_addr(), proxy);
+        Ok(())
+    }
+}
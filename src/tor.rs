@@ -0,0 +1,115 @@
+//! Helpers for Tor's SOCKS5 extensions.
+
+use crate::{
+    connector::Socks5Connector,
+    tcp::{ConnectFuture, Socks5Stream},
+    IntoTargetAddr, Result, ToProxyAddrs,
+};
+use std::net::SocketAddr;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A generator of credentials for Tor's stream isolation.
+///
+/// Tor puts streams which use different SOCKS username/password pairs on different
+/// circuits (the `IsolateSOCKSAuth` flag, which is on by default). Each `IsolationToken`
+/// returned by `new_session` holds a pair which is unique to this generator, so the streams
+/// of a logical session share a circuit and the streams of different sessions do not.
+#[derive(Debug)]
+pub struct TorIsolation {
+    prefix: String,
+    next: AtomicUsize,
+}
+
+impl TorIsolation {
+    /// Creates a `TorIsolation`.
+    ///
+    /// The credentials include the process ID and the creation time, so generators of
+    /// different processes using the same Tor instance do not collide.
+    pub fn new() -> Self {
+        static GENERATORS: AtomicUsize = AtomicUsize::new(0);
+        let generator = GENERATORS.fetch_add(1, Ordering::Relaxed);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos());
+        TorIsolation {
+            prefix: format!("tokio-socks-{}-{:x}-{}", process::id(), nanos, generator),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the credentials of a new session.
+    pub fn new_session(&self) -> IsolationToken {
+        let session = self.next.fetch_add(1, Ordering::Relaxed);
+        IsolationToken {
+            username: format!("{}-{}", self.prefix, session),
+            password: "isolated".to_owned(),
+        }
+    }
+}
+
+impl Default for TorIsolation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The credentials of an isolated session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsolationToken {
+    username: String,
+    password: String,
+}
+
+impl IsolationToken {
+    /// Returns the username sent to Tor.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Returns the password sent to Tor.
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    /// Connects to a target server through a Tor proxy on the circuit of this session.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect<'a, 't, P, T>(
+        &'a self,
+        proxy: P,
+        target: T,
+    ) -> Result<ConnectFuture<'a, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Socks5Stream::connect_with_password(proxy, target, &self.username, &self.password)
+    }
+
+    /// Returns a `Socks5Connector` whose connections use the circuit of this session.
+    pub fn connector(&self, proxy: SocketAddr) -> Socks5Connector {
+        Socks5Connector::with_password(proxy, self.username.as_str(), self.password.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_get_distinct_credentials() {
+        let isolation = TorIsolation::new();
+        let first = isolation.new_session();
+        let second = isolation.new_session();
+        assert_ne!(first, second);
+        assert!(first.username().len() <= 255);
+        assert_ne!(
+            TorIsolation::new().new_session().username(),
+            first.username()
+        );
+    }
+}
@@ -0,0 +1,469 @@
+//! SOCKS5 UDP ASSOCIATE client.
+
+use crate::{
+    tcp::{self, Command, ConnectFuture, Socks5Stream},
+    Authentication, Error, IntoTargetAddr, Result, TargetAddr, ToProxyAddrs,
+};
+use futures::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    try_ready, Async, Future, Poll, Stream,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio_io::AsyncRead;
+use tokio_udp::UdpSocket;
+
+/// The maximum length of the header of a UDP request.
+const MAX_HEADER_LEN: usize = 3 + TargetAddr::MAX_ENCODED_LEN;
+
+/// The maximum length of the payload of a UDP datagram.
+pub(crate) const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// A SOCKS5 UDP client.
+///
+/// The association lasts as long as the TCP control connection, which is owned by the
+/// `Socks5Datagram`. Datagrams are sent to and received from the relay address of the proxy
+/// server, with the UDP request header of RFC 1928 added and removed. Fragmented datagrams
+/// are not supported and are dropped when received.
+#[derive(Debug)]
+pub struct Socks5Datagram {
+    socket: UdpSocket,
+    stream: Socks5Stream,
+    relay_addr: SocketAddr,
+    relay_addr_substituted: bool,
+    max_domain_len: u8,
+    buf: Vec<u8>,
+}
+
+impl Socks5Datagram {
+    /// Binds a UDP socket to `local_addr` and associates it with the specified proxy.
+    ///
+    /// # Error
+    ///
+    /// It fails if the UDP socket cannot be bound.
+    pub fn bind<P>(proxy: P, local_addr: &SocketAddr) -> Result<DatagramFuture<'static, P::Output>>
+    where
+        P: ToProxyAddrs,
+    {
+        Self::bind_raw(proxy, local_addr, Authentication::None)
+    }
+
+    /// Binds a UDP socket to `local_addr` and associates it with the specified proxy using
+    /// given username and password.
+    ///
+    /// # Error
+    ///
+    /// It fails if the UDP socket cannot be bound or the username or password is invalid.
+    pub fn bind_with_password<'a, P>(
+        proxy: P,
+        local_addr: &SocketAddr,
+        username: &'a str,
+        password: &'a str,
+    ) -> Result<DatagramFuture<'a, P::Output>>
+    where
+        P: ToProxyAddrs,
+    {
+        Self::bind_with_password_bytes(proxy, local_addr, username.as_bytes(), password.as_bytes())
+    }
+
+    /// Binds a UDP socket to `local_addr` and associates it with the specified proxy using
+    /// given username and password as raw bytes.
+    ///
+    /// # Error
+    ///
+    /// It fails if the UDP socket cannot be bound or the username or password is invalid.
+    pub fn bind_with_password_bytes<'a, P>(
+        proxy: P,
+        local_addr: &SocketAddr,
+        username: &'a [u8],
+        password: &'a [u8],
+    ) -> Result<DatagramFuture<'a, P::Output>>
+    where
+        P: ToProxyAddrs,
+    {
+        Self::bind_raw(
+            proxy,
+            local_addr,
+            Authentication::Password {
+                username: username.into(),
+                password: password.into(),
+            },
+        )
+    }
+
+    fn bind_raw<'a, P>(
+        proxy: P,
+        local_addr: &SocketAddr,
+        auth: Authentication<'a>,
+    ) -> Result<DatagramFuture<'a, P::Output>>
+    where
+        P: ToProxyAddrs,
+    {
+        let socket = UdpSocket::bind(local_addr)?;
+        // The proxy server may only accept datagrams from the address sent in the request.
+        let conn =
+            Socks5Stream::connect_raw(proxy, socket.local_addr()?, auth, Command::Associate)?;
+        Ok(DatagramFuture {
+            conn,
+            socket: Some(socket),
+            max_domain_len: 255,
+            relay_addr_fallback: true,
+        })
+    }
+
+    /// Sends a datagram to `target` through the proxy, returning the number of bytes of
+    /// `buf` which were sent.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn poll_send_to<'t, T>(&mut self, buf: &[u8], target: T) -> Poll<usize, Error>
+    where
+        T: IntoTargetAddr<'t>,
+    {
+        let target = target.into_target_addr()?;
+        self.buf[..3].copy_from_slice(&[0x00, 0x00, 0x00]);
+        let header_len = 3 + target.encode(&mut self.buf[3..]);
+        let len = header_len + buf.len();
+        if self.buf.len() < len {
+            self.buf.resize(len, 0);
+        }
+        self.buf[header_len..len].copy_from_slice(buf);
+        let n = try_ready!(self.socket.poll_send_to(&self.buf[..len], &self.relay_addr));
+        Ok(Async::Ready(n.saturating_sub(header_len)))
+    }
+
+    /// Receives a datagram through the proxy, returning the number of bytes read into `buf`
+    /// and the address of the sender.
+    ///
+    /// The datagram is truncated if `buf` is too small. Datagrams which are not sent by the
+    /// relay, are fragmented, have an invalid header or a domain name in the header longer
+    /// than the limit set with `DatagramFuture::with_max_reply_domain_len` are dropped.
+    pub fn poll_recv_from(&mut self, buf: &mut [u8]) -> Poll<(usize, TargetAddr<'static>), Error> {
+        if self.buf.len() < MAX_HEADER_LEN + buf.len() {
+            self.buf.resize(MAX_HEADER_LEN + buf.len(), 0);
+        }
+        loop {
+            let (n, from) = try_ready!(self.socket.poll_recv_from(&mut self.buf));
+            if from != self.relay_addr || n < 3 || self.buf[..3] != [0x00, 0x00, 0x00] {
+                continue;
+            }
+            let addr_len = match tcp::address_len(&self.buf[3..n]) {
+                Ok(Some(len)) => len,
+                _ => continue,
+            };
+            if self.buf[3] == 0x03 && self.buf[4] > self.max_domain_len {
+                continue;
+            }
+            let target = match tcp::parse_address(&self.buf[3..(3 + addr_len)]) {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+            let data = &self.buf[(3 + addr_len)..n];
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            return Ok(Async::Ready((len, target)));
+        }
+    }
+
+    /// Consumes the `Socks5Datagram`, returning a `Future` which sends `buf` to `target`
+    /// and resolves to the `Socks5Datagram` and the buffer.
+    pub fn send_dgram<T>(self, buf: T, target: TargetAddr<'static>) -> SendDgram<T>
+    where
+        T: AsRef<[u8]>,
+    {
+        SendDgram(Some((self, buf, target)))
+    }
+
+    /// Consumes the `Socks5Datagram`, returning a `Future` which receives a datagram into
+    /// `buf` and resolves to the `Socks5Datagram`, the buffer, the number of bytes read and
+    /// the address of the sender.
+    pub fn recv_dgram<T>(self, buf: T) -> RecvDgram<T>
+    where
+        T: AsMut<[u8]>,
+    {
+        RecvDgram(Some((self, buf)))
+    }
+
+    /// Polls whether the control connection has been closed, which ends the association.
+    ///
+    /// The proxy server is not supposed to send anything on the control connection, so
+    /// received data is discarded. A failed connection counts as closed.
+    pub fn poll_closed(&mut self) -> Poll<(), Error> {
+        let mut buf = [0; 64];
+        loop {
+            match self.stream.poll_read(&mut buf) {
+                Ok(Async::Ready(0)) | Err(_) => return Ok(Async::Ready(())),
+                Ok(Async::Ready(_)) => {}
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+            }
+        }
+    }
+
+    /// Returns the address of the UDP relay of the proxy server.
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    /// Returns whether the proxy server replied with an unspecified relay address, which
+    /// was replaced with the IP address of the control connection.
+    pub fn relay_addr_substituted(&self) -> bool {
+        self.relay_addr_substituted
+    }
+
+    /// Returns the address of the proxy server.
+    pub fn proxy_addr(&self) -> SocketAddr {
+        self.stream.proxy_addr()
+    }
+
+    /// Returns a reference to the underlying UDP socket.
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Consumes the `Socks5Datagram`, returning the UDP socket and the control connection.
+    ///
+    /// The association ends when the control connection is closed.
+    pub fn into_parts(self) -> (UdpSocket, Socks5Stream) {
+        (self.socket, self.stream)
+    }
+
+    /// Consumes the `Socks5Datagram`, returning a `Demux` which routes received datagrams
+    /// into per-peer channels.
+    pub fn demux(self) -> Demux {
+        Demux {
+            datagram: self,
+            peers: HashMap::new(),
+            buf: vec![0; MAX_DATAGRAM_LEN],
+        }
+    }
+}
+
+/// A `Future` which resolves to a `Socks5Datagram` associated with the proxy.
+pub struct DatagramFuture<'a, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    conn: ConnectFuture<'a, 'static, S>,
+    socket: Option<UdpSocket>,
+    max_domain_len: u8,
+    relay_addr_fallback: bool,
+}
+
+impl<'a, S> DatagramFuture<'a, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    /// Limits the length of a domain name in the reply of the proxy server and in the
+    /// headers of received datagrams to `max` bytes.
+    ///
+    /// The reply fails with `ReplyDomainTooLong`, while datagrams are dropped. The default
+    /// is 255, the most the protocol allows.
+    pub fn with_max_reply_domain_len(mut self, max: u8) -> Self {
+        self.conn = self.conn.with_max_reply_domain_len(max);
+        self.max_domain_len = max;
+        self
+    }
+
+    /// Sets whether an unspecified relay address in the reply, e.g. `0.0.0.0:5000`, is
+    /// replaced with the IP address of the control connection.
+    ///
+    /// Many proxy servers reply so when the relay listens on the same host as the control
+    /// connection. It is on by default. When it is off, such a reply fails with
+    /// `InvalidTargetAddress`.
+    pub fn with_relay_addr_fallback(mut self, enabled: bool) -> Self {
+        self.relay_addr_fallback = enabled;
+        self
+    }
+}
+
+impl<'a, S> Future for DatagramFuture<'a, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    type Item = Socks5Datagram;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let stream = try_ready!(self.conn.poll());
+        let (relay_addr, relay_addr_substituted) = match stream.target_addr() {
+            TargetAddr::Ip(addr) if addr.ip().is_unspecified() => {
+                if !self.relay_addr_fallback {
+                    Err(Error::InvalidTargetAddress("relay address is unspecified"))?
+                }
+                (SocketAddr::new(stream.proxy_addr().ip(), addr.port()), true)
+            }
+            TargetAddr::Ip(addr) => (addr, false),
+            TargetAddr::Domain(..) => Err(Error::InvalidTargetAddress(
+                "relay address is not an IP address",
+            ))?,
+        };
+        Ok(Async::Ready(Socks5Datagram {
+            socket: self.socket.take().unwrap(),
+            stream,
+            relay_addr,
+            relay_addr_substituted,
+            max_domain_len: self.max_domain_len,
+            buf: vec![0; MAX_HEADER_LEN],
+        }))
+    }
+}
+
+/// A `Future` which sends a datagram through the proxy.
+pub struct SendDgram<T>(Option<(Socks5Datagram, T, TargetAddr<'static>)>);
+
+impl<T> Future for SendDgram<T>
+where
+    T: AsRef<[u8]>,
+{
+    type Item = (Socks5Datagram, T);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let (datagram, buf, target) = self.0.as_mut().expect("polled after completion");
+            try_ready!(datagram.poll_send_to(buf.as_ref(), target.to_owned()));
+        }
+        let (datagram, buf, _) = self.0.take().unwrap();
+        Ok(Async::Ready((datagram, buf)))
+    }
+}
+
+/// A `Future` which receives a datagram through the proxy.
+pub struct RecvDgram<T>(Option<(Socks5Datagram, T)>);
+
+impl<T> Future for RecvDgram<T>
+where
+    T: AsMut<[u8]>,
+{
+    type Item = (Socks5Datagram, T, usize, TargetAddr<'static>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let (len, from) = {
+            let (datagram, buf) = self.0.as_mut().expect("polled after completion");
+            try_ready!(datagram.poll_recv_from(buf.as_mut()))
+        };
+        let (datagram, buf) = self.0.take().unwrap();
+        Ok(Async::Ready((datagram, buf, len, from)))
+    }
+}
+
+/// A `Stream` which routes the datagrams received through a `Socks5Datagram` into per-peer
+/// channels.
+///
+/// Datagrams from a registered peer are sent to its channel, and the stream yields the
+/// datagrams from other peers with their sender. Peers are keyed by the sender address in
+/// the header exactly as the relay reports it, which is usually an IP address even if the
+/// datagrams were sent to a domain name. The stream must be polled for the channels to
+/// receive anything.
+pub struct Demux {
+    datagram: Socks5Datagram,
+    peers: HashMap<TargetAddr<'static>, UnboundedSender<Vec<u8>>>,
+    buf: Vec<u8>,
+}
+
+impl Demux {
+    /// Returns a channel of the datagrams received from `peer`, replacing the previous
+    /// channel of `peer`.
+    ///
+    /// The peer is unregistered when the receiver is dropped, after which its datagrams are
+    /// yielded by the stream again.
+    pub fn register(&mut self, peer: TargetAddr<'static>) -> UnboundedReceiver<Vec<u8>> {
+        let (tx, rx) = mpsc::unbounded();
+        self.peers.insert(peer, tx);
+        rx
+    }
+
+    /// Returns a mutable reference to the `Socks5Datagram`, e.g. to send datagrams.
+    pub fn get_mut(&mut self) -> &mut Socks5Datagram {
+        &mut self.datagram
+    }
+
+    /// Consumes the `Demux`, returning the `Socks5Datagram`.
+    ///
+    /// The channels of the registered peers end.
+    pub fn into_inner(self) -> Socks5Datagram {
+        self.datagram
+    }
+}
+
+impl Stream for Demux {
+    type Item = (Vec<u8>, TargetAddr<'static>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Error> {
+        loop {
+            let (len, from) = try_ready!(self.datagram.poll_recv_from(&mut self.buf));
+            let data = self.buf[..len].to_vec();
+            let data = match self.peers.get(&from) {
+                Some(tx) => match tx.unbounded_send(data) {
+                    Ok(()) => continue,
+                    Err(e) => e.into_inner(),
+                },
+                None => data,
+            };
+            self.peers.remove(&from);
+            return Ok(Async::Ready(Some((data, from))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Spawns a proxy server which accepts the association with `reply`, then waits for the
+    /// client to close the connection.
+    fn proxy(reply: &'static [u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            for reply in &[&b"\x05\x00"[..], reply] {
+                if tcp.read(&mut buf).unwrap_or(0) == 0 {
+                    return;
+                }
+                tcp.write_all(reply).unwrap();
+            }
+            while tcp.read(&mut buf).unwrap_or(0) > 0 {}
+        });
+        addr
+    }
+
+    fn bind(proxy: SocketAddr, fallback: bool) -> Result<Socks5Datagram> {
+        let local_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let conn = Socks5Datagram::bind(proxy, &local_addr)?.with_relay_addr_fallback(fallback);
+        tokio::runtime::current_thread::block_on_all(conn)
+    }
+
+    #[test]
+    fn unspecified_relay_addr_is_substituted() -> Result<()> {
+        let datagram = bind(proxy(b"\x05\x00\x00\x01\0\0\0\0\x13\x88"), true)?;
+        assert_eq!(
+            datagram.relay_addr(),
+            SocketAddr::from(([127, 0, 0, 1], 5000))
+        );
+        assert!(datagram.relay_addr_substituted());
+
+        let datagram = bind(proxy(b"\x05\x00\x00\x01\x7f\0\0\x02\x13\x88"), true)?;
+        assert_eq!(
+            datagram.relay_addr(),
+            SocketAddr::from(([127, 0, 0, 2], 5000))
+        );
+        assert!(!datagram.relay_addr_substituted());
+        Ok(())
+    }
+
+    #[test]
+    fn relay_addr_fallback_can_be_turned_off() {
+        match bind(proxy(b"\x05\x00\x00\x01\0\0\0\0\x13\x88"), false) {
+            Err(Error::InvalidTargetAddress(_)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
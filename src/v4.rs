@@ -0,0 +1,790 @@
+//! SOCKS4 client.
+//!
+//! Domain name targets are sent to the proxy server using the SOCKS4a extension, so they
+//! are resolved by the proxy server. `SocksStream::connect_auto` speaks SOCKS5 or SOCKS4,
+//! depending on the proxy server.
+
+use crate::{
+    connector::{BoxConnectFuture, ProxyConnect},
+    tcp::{self, poll_read_some, poll_write_some, Command, Socks5Stream},
+    Authentication, Error, IntoTargetAddr, Result, TargetAddr, ToProxyAddrs,
+};
+use bytes::{Buf, BufMut};
+use derefable::Derefable;
+use futures::{
+    future,
+    stream::{self, Collect, IterOk},
+    try_ready, Async, Future, Poll, Stream,
+};
+use std::borrow::{Borrow, Cow};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::vec;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tcp::{ConnectFuture as TokioConnect, TcpStream};
+
+/// A SOCKS4 client.
+///
+/// For convenience, it can be dereferenced to `tokio_tcp::TcpStream`.
+#[derive(Debug, Derefable)]
+pub struct Socks4Stream {
+    #[deref(mutable)]
+    tcp: TcpStream,
+    proxy_addr: SocketAddr,
+    target: TargetAddr<'static>,
+}
+
+impl Socks4Stream {
+    /// Connects to a target server through a SOCKS4 proxy.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    /// SOCKS4 does not support IPv6, so it fails if the target is an IPv6 address.
+    pub fn connect<'t, P, T>(
+        proxy: P,
+        target: T,
+    ) -> Result<Socks4ConnectFuture<'static, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        Self::connect_with_userid(proxy, target, "")
+    }
+
+    /// Connects to a target server through a SOCKS4 proxy using given user ID.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    /// It fails if the target is an IPv6 address, or if the user ID is longer than 255 bytes
+    /// or contains a NUL byte.
+    pub fn connect_with_userid<'a, 't, P, T>(
+        proxy: P,
+        target: T,
+        user_id: &'a str,
+    ) -> Result<Socks4ConnectFuture<'a, 't, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        let target = target.into_target_addr()?;
+        Self::connect_raw(proxy.to_proxy_addrs(), target, user_id.into())
+    }
+
+    fn connect_raw<'a, 't, S>(
+        proxy: S,
+        target: TargetAddr<'t>,
+        user_id: Cow<'a, str>,
+    ) -> Result<Socks4ConnectFuture<'a, 't, S>>
+    where
+        S: Stream<Item = SocketAddr, Error = Error>,
+    {
+        if user_id.len() > 255 || user_id.contains('\0') {
+            Err(Error::InvalidAuthValues(
+                "user ID should be at most 255 bytes without NUL",
+            ))?
+        }
+        match target {
+            TargetAddr::Ip(SocketAddr::V4(_)) => {}
+            TargetAddr::Ip(SocketAddr::V6(_)) => Err(Error::InvalidTargetAddress(
+                "SOCKS4 does not support IPv6 addresses",
+            ))?,
+            TargetAddr::Domain(ref domain, _) if domain.contains('\0') => {
+                Err(Error::InvalidTargetAddress("domain contains a NUL byte"))?
+            }
+            TargetAddr::Domain(..) => {}
+        }
+        Ok(Socks4ConnectFuture {
+            user_id: match user_id {
+                Cow::Borrowed(user_id) => Cow::Borrowed(user_id.as_bytes()),
+                Cow::Owned(user_id) => Cow::Owned(user_id.into_bytes()),
+            },
+            proxy,
+            target,
+            proxy_addr: None,
+            allow_port_zero: false,
+            state: ConnectState::Uninitialized,
+            buf: [0; 520],
+            ptr: 0,
+            len: 0,
+        })
+    }
+
+    /// Consumes the `Socks4Stream`, returning the inner `tokio_tcp::TcpStream`.
+    pub fn into_inner(self) -> TcpStream {
+        self.tcp
+    }
+
+    /// Returns the target address that the proxy server connects to.
+    pub fn target_addr(&self) -> TargetAddr<'_> {
+        match &self.target {
+            TargetAddr::Ip(addr) => TargetAddr::Ip(*addr),
+            TargetAddr::Domain(domain, port) => {
+                let domain: &str = domain.borrow();
+                TargetAddr::Domain(domain.into(), *port)
+            }
+        }
+    }
+
+    /// Returns the address of the proxy server this stream is connected to.
+    pub fn proxy_addr(&self) -> SocketAddr {
+        self.proxy_addr
+    }
+}
+
+/// A `Future` which resolves to a socket to the target server through a SOCKS4 proxy.
+///
+/// The proxy addresses are tried in order until a connection is established.
+pub struct Socks4ConnectFuture<'a, 't, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    user_id: Cow<'a, [u8]>,
+    proxy: S,
+    target: TargetAddr<'t>,
+    proxy_addr: Option<SocketAddr>,
+    allow_port_zero: bool,
+    state: ConnectState,
+    buf: [u8; 520],
+    ptr: usize,
+    len: usize,
+}
+
+impl<'a, 't, S> Socks4ConnectFuture<'a, 't, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    /// Allows a request to port 0 of the target, which otherwise fails with
+    /// `InvalidTargetAddress` before connecting to the proxy server, like
+    /// `ConnectFuture::allow_port_zero`.
+    pub fn allow_port_zero(mut self) -> Self {
+        self.allow_port_zero = true;
+        self
+    }
+
+    fn prepare_send_request(&mut self) {
+        self.ptr = 0;
+        self.buf[..2].copy_from_slice(&[0x04, 0x01]);
+        match &self.target {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                self.buf[2..4].copy_from_slice(&addr.port().to_be_bytes());
+                self.buf[4..8].copy_from_slice(&addr.ip().octets());
+            }
+            TargetAddr::Domain(_, port) => {
+                // SOCKS4a: an invalid IP address of the form 0.0.0.x, followed by the domain
+                // after the user ID.
+                self.buf[2..4].copy_from_slice(&port.to_be_bytes());
+                self.buf[4..8].copy_from_slice(&[0, 0, 0, 1]);
+            }
+            TargetAddr::Ip(SocketAddr::V6(_)) => unreachable!(),
+        }
+        let user_id_len = self.user_id.len();
+        self.buf[8..(8 + user_id_len)].copy_from_slice(&self.user_id);
+        self.buf[8 + user_id_len] = 0x00;
+        self.len = 9 + user_id_len;
+        if let TargetAddr::Domain(domain, _) = &self.target {
+            let domain = domain.as_bytes();
+            self.buf[self.len..(self.len + domain.len())].copy_from_slice(domain);
+            self.len += domain.len();
+            self.buf[self.len] = 0x00;
+            self.len += 1;
+        }
+    }
+
+    fn prepare_recv_reply(&mut self) {
+        self.ptr = 0;
+        self.len = 8;
+    }
+}
+
+impl<'a, 't, S> Future for Socks4ConnectFuture<'a, 't, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    type Item = Socks4Stream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Socks4Stream, Error> {
+        loop {
+            match self.state {
+                ConnectState::Uninitialized if !self.allow_port_zero && self.target.port() == 0 => {
+                    Err(Error::InvalidTargetAddress(
+                        "port 0 is not a valid target port",
+                    ))?
+                }
+                ConnectState::Uninitialized => match try_ready!(self.proxy.poll()) {
+                    Some(addr) => {
+                        self.proxy_addr = Some(addr);
+                        self.state = ConnectState::Created(TcpStream::connect(&addr));
+                    }
+                    None => Err(Error::ProxyServerUnreachable)?,
+                },
+                ConnectState::Created(ref mut conn_fut) => match conn_fut.poll() {
+                    Ok(Async::Ready(tcp)) => {
+                        self.state = ConnectState::SendRequest(Some(tcp));
+                        self.prepare_send_request();
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(_e) => self.state = ConnectState::Uninitialized,
+                },
+                ConnectState::SendRequest(ref mut opt) => {
+                    let tcp = opt.as_mut().unwrap();
+                    self.ptr += try_ready!(poll_write_some(tcp, &self.buf[self.ptr..self.len]));
+                    if self.ptr == self.len {
+                        self.state = ConnectState::RequestSent(opt.take());
+                        self.prepare_recv_reply();
+                    }
+                }
+                ConnectState::RequestSent(ref mut opt) => {
+                    let tcp = opt.as_mut().unwrap();
+                    self.ptr += try_ready!(poll_read_some(tcp, &mut self.buf[self.ptr..self.len]));
+                    if self.ptr == self.len {
+                        if self.buf[0] != 0x00 {
+                            Err(Error::InvalidResponseVersion {
+                                expected: 0x00,
+                                found: self.buf[0],
+                            })?
+                        }
+                        match self.buf[1] {
+                            0x5a => {} // granted
+                            0x5b => Err(Error::Socks4RequestRejected)?,
+                            0x5c => Err(Error::IdentdUnreachable)?,
+                            0x5d => Err(Error::IdentdUserIdMismatch)?,
+                            _ => Err(Error::UnknownError)?,
+                        }
+                        return Ok(Async::Ready(Socks4Stream {
+                            tcp: opt.take().unwrap(),
+                            proxy_addr: self.proxy_addr.unwrap(),
+                            target: self.target.to_owned(),
+                        }));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ConnectState {
+    Uninitialized,
+    Created(TokioConnect),
+    SendRequest(Option<TcpStream>),
+    RequestSent(Option<TcpStream>),
+}
+
+/// A `ProxyConnect` which connects through a SOCKS4 proxy.
+///
+/// Domain name targets are sent using the SOCKS4a extension, like with `Socks4Stream`.
+#[derive(Debug, Clone)]
+pub struct Socks4Connector {
+    proxy: SocketAddr,
+    user_id: String,
+}
+
+impl Socks4Connector {
+    /// Creates a connector for the given SOCKS4 proxy.
+    pub fn new(proxy: SocketAddr) -> Self {
+        Self::with_userid(proxy, String::new())
+    }
+
+    /// Creates a connector for the given SOCKS4 proxy using given user ID.
+    pub fn with_userid<U>(proxy: SocketAddr, user_id: U) -> Self
+    where
+        U: Into<String>,
+    {
+        Socks4Connector {
+            proxy,
+            user_id: user_id.into(),
+        }
+    }
+}
+
+impl ProxyConnect for Socks4Connector {
+    fn connect(&self, target: TargetAddr<'static>) -> BoxConnectFuture {
+        let user_id = Cow::Owned(self.user_id.clone());
+        match Socks4Stream::connect_raw(self.proxy.to_proxy_addrs(), target, user_id) {
+            Ok(conn) => Box::new(conn.map(|stream| Box::new(stream) as _)),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+}
+
+impl Read for Socks4Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tcp.read(buf)
+    }
+}
+
+impl Write for Socks4Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tcp.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tcp.flush()
+    }
+}
+
+impl AsyncRead for Socks4Stream {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        AsyncRead::prepare_uninitialized_buffer(&self.tcp, buf)
+    }
+
+    fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        AsyncRead::read_buf(&mut self.tcp, buf)
+    }
+}
+
+impl AsyncWrite for Socks4Stream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut self.tcp)
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        AsyncWrite::write_buf(&mut self.tcp, buf)
+    }
+}
+
+impl Read for &Socks4Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &self.tcp, buf)
+    }
+}
+
+impl Write for &Socks4Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Write::write(&mut &self.tcp, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Write::flush(&mut &self.tcp)
+    }
+}
+
+impl AsyncRead for &Socks4Stream {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        AsyncRead::prepare_uninitialized_buffer(&self.tcp, buf)
+    }
+
+    fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        AsyncRead::read_buf(&mut &self.tcp, buf)
+    }
+}
+
+impl AsyncWrite for &Socks4Stream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        AsyncWrite::shutdown(&mut &self.tcp)
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        AsyncWrite::write_buf(&mut &self.tcp, buf)
+    }
+}
+
+/// A version of the SOCKS protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// SOCKS4, including the SOCKS4a extension.
+    V4,
+    /// SOCKS5.
+    V5,
+}
+
+/// A client of a proxy server which speaks either SOCKS5 or SOCKS4.
+#[derive(Debug)]
+pub enum SocksStream {
+    /// The proxy server speaks SOCKS5.
+    V5(Socks5Stream),
+    /// The proxy server speaks SOCKS4.
+    V4(Socks4Stream),
+}
+
+impl SocksStream {
+    /// Connects to a target server through a proxy whose version is unknown, e.g. one from a
+    /// user-supplied proxy list.
+    ///
+    /// SOCKS5 is tried first. If the proxy server answers the SOCKS5 greeting with another
+    /// version or closes the connection without a reply, the target is requested again
+    /// using SOCKS4, or SOCKS4a for domain names, unless it is turned off with
+    /// `ConnectAutoFuture::with_fallback`.
+    ///
+    /// # Error
+    ///
+    /// It propagates the error that occurs in the conversion from `T` to `TargetAddr`.
+    pub fn connect_auto<'t, P, T>(proxy: P, target: T) -> Result<ConnectAutoFuture<'t, P::Output>>
+    where
+        P: ToProxyAddrs,
+        T: IntoTargetAddr<'t>,
+    {
+        let target = target.into_target_addr()?;
+        Ok(ConnectAutoFuture {
+            state: AutoState::Resolving(proxy.to_proxy_addrs().collect(), Some(target)),
+            fallback: true,
+        })
+    }
+
+    /// Returns the version of the protocol the proxy server speaks.
+    pub fn version(&self) -> Version {
+        match self {
+            SocksStream::V5(_) => Version::V5,
+            SocksStream::V4(_) => Version::V4,
+        }
+    }
+
+    /// Returns the target address that the proxy server connects to.
+    pub fn target_addr(&self) -> TargetAddr<'_> {
+        match self {
+            SocksStream::V5(stream) => stream.target_addr(),
+            SocksStream::V4(stream) => stream.target_addr(),
+        }
+    }
+
+    /// Returns the address of the proxy server this stream is connected to.
+    pub fn proxy_addr(&self) -> SocketAddr {
+        match self {
+            SocksStream::V5(stream) => stream.proxy_addr(),
+            SocksStream::V4(stream) => stream.proxy_addr(),
+        }
+    }
+
+    /// Consumes the `SocksStream`, returning the inner `tokio_tcp::TcpStream`.
+    pub fn into_inner(self) -> TcpStream {
+        match self {
+            SocksStream::V5(stream) => stream.into_inner(),
+            SocksStream::V4(stream) => stream.into_inner(),
+        }
+    }
+}
+
+/// A `Future` which resolves to a `SocksStream` to the target server.
+pub struct ConnectAutoFuture<'t, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    state: AutoState<'t, S>,
+    fallback: bool,
+}
+
+impl<'t, S> ConnectAutoFuture<'t, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    /// Sets whether SOCKS4 is tried when the proxy server does not speak SOCKS5. It is on
+    /// by default.
+    ///
+    /// With the fallback turned off, the future fails with the error of the SOCKS5
+    /// negotiation, e.g. for tooling which only probes the version of a proxy server.
+    pub fn with_fallback(mut self, enabled: bool) -> Self {
+        self.fallback = enabled;
+        self
+    }
+}
+
+/// The proxy addresses, which are resolved once for both versions.
+type ProxyAddrs = IterOk<vec::IntoIter<SocketAddr>, Error>;
+
+enum AutoState<'t, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    Resolving(Collect<S>, Option<TargetAddr<'t>>),
+    V5(
+        Box<tcp::ConnectFuture<'static, 't, ProxyAddrs>>,
+        Option<(ProxyAddrs, TargetAddr<'static>)>,
+    ),
+    V4(Box<Socks4ConnectFuture<'static, 'static, ProxyAddrs>>),
+}
+
+impl<'t, S> Future for ConnectAutoFuture<'t, S>
+where
+    S: Stream<Item = SocketAddr, Error = Error>,
+{
+    type Item = SocksStream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<SocksStream, Error> {
+        loop {
+            let fallback = match &mut self.state {
+                AutoState::Resolving(addrs, target) => {
+                    let addrs = try_ready!(addrs.poll());
+                    let target = target.take().unwrap();
+                    let fallback = if self.fallback {
+                        Some((stream::iter_ok(addrs.clone()), target.to_owned()))
+                    } else {
+                        None
+                    };
+                    let conn = tcp::ConnectFuture::new(
+                        Authentication::None,
+                        Command::Connect,
+                        stream::iter_ok(addrs),
+                        target,
+                    );
+                    self.state = AutoState::V5(Box::new(conn), fallback);
+                    continue;
+                }
+                AutoState::V5(conn, fallback) => match conn.poll() {
+                    Ok(Async::Ready(stream)) => return Ok(Async::Ready(SocksStream::V5(stream))),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(e) => {
+                        // Only the answer to the greeting tells the versions apart. A later
+                        // failure means the server speaks SOCKS5 and may have already
+                        // connected to the target.
+                        let other_version = conn.failed_in_method_selection()
+                            && match &e {
+                                Error::InvalidResponseVersion { expected: 0x05, .. } => true,
+                                Error::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+                                _ => false,
+                            };
+                        match fallback.take() {
+                            Some(fallback) if other_version => fallback,
+                            _ => return Err(e),
+                        }
+                    }
+                },
+                AutoState::V4(conn) => {
+                    return Ok(Async::Ready(SocksStream::V4(try_ready!(conn.poll()))));
+                }
+            };
+            let (proxy, target) = fallback;
+            let conn = Socks4Stream::connect_raw(proxy, target, "".into())?;
+            self.state = AutoState::V4(Box::new(conn));
+        }
+    }
+}
+
+impl Read for SocksStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SocksStream::V5(stream) => stream.read(buf),
+            SocksStream::V4(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for SocksStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SocksStream::V5(stream) => stream.write(buf),
+            SocksStream::V4(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SocksStream::V5(stream) => stream.flush(),
+            SocksStream::V4(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for SocksStream {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        match self {
+            SocksStream::V5(stream) => AsyncRead::prepare_uninitialized_buffer(stream, buf),
+            SocksStream::V4(stream) => AsyncRead::prepare_uninitialized_buffer(stream, buf),
+        }
+    }
+
+    fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        match self {
+            SocksStream::V5(stream) => AsyncRead::read_buf(stream, buf),
+            SocksStream::V4(stream) => AsyncRead::read_buf(stream, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SocksStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            SocksStream::V5(stream) => AsyncWrite::shutdown(stream),
+            SocksStream::V4(stream) => AsyncWrite::shutdown(stream),
+        }
+    }
+
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        match self {
+            SocksStream::V5(stream) => AsyncWrite::write_buf(stream, buf),
+            SocksStream::V4(stream) => AsyncWrite::write_buf(stream, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Spawns a proxy server which reads the request, sends `reply` and returns the request
+    /// through the handle.
+    fn proxy(reply: &'static [u8]) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let n = tcp.read(&mut buf).unwrap();
+            tcp.write_all(reply).unwrap();
+            buf[..n].to_vec()
+        });
+        (addr, handle)
+    }
+
+    fn connect(proxy: SocketAddr, user_id: &str) -> Result<Socks4Stream> {
+        let conn = Socks4Stream::connect_with_userid(proxy, "10.0.0.1:80", user_id)?;
+        tokio::runtime::current_thread::block_on_all(conn)
+    }
+
+    #[test]
+    fn sends_connect_request() -> Result<()> {
+        let (addr, handle) = proxy(b"\x00\x5a\0\0\0\0\0\0");
+        let stream = connect(addr, "me")?;
+        assert_eq!(handle.join().unwrap(), b"\x04\x01\x00\x50\x0a\0\0\x01me\0");
+        assert_eq!(stream.target_addr(), "10.0.0.1:80".into_target_addr()?);
+        assert_eq!(stream.proxy_addr(), addr);
+        Ok(())
+    }
+
+    #[test]
+    fn sends_domain_using_socks4a() -> Result<()> {
+        let (addr, handle) = proxy(b"\x00\x5a\0\0\0\0\0\0");
+        let conn = Socks4Stream::connect(addr, "example.com:443")?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(
+            handle.join().unwrap(),
+            &b"\x04\x01\x01\xbb\0\0\0\x01\0example.com\0"[..]
+        );
+        assert_eq!(stream.target_addr(), "example.com:443".into_target_addr()?);
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_request_fails() {
+        for (reply, expected) in &[
+            (
+                &b"\x00\x5b\0\0\0\0\0\0"[..],
+                "SOCKS4 request rejected or failed",
+            ),
+            (
+                &b"\x00\x5c\0\0\0\0\0\0"[..],
+                "SOCKS4 request rejected, identd unreachable",
+            ),
+            (
+                &b"\x00\x5d\0\0\0\0\0\0"[..],
+                "SOCKS4 request rejected, user ID mismatch",
+            ),
+        ] {
+            let (addr, _) = proxy(reply);
+            match connect(addr, "") {
+                Err(e) => assert_eq!(e.to_string(), *expected),
+                Ok(_) => panic!("request should be rejected"),
+            }
+        }
+    }
+
+    #[test]
+    fn connector_works_behind_dyn_connector() -> Result<()> {
+        use crate::connector::DynConnector;
+
+        let (addr, handle) = proxy(b"\x00\x5a\0\0\0\0\0\0");
+        let connector: DynConnector = Box::new(Socks4Connector::with_userid(addr, "me"));
+        let conn = connector.connect("10.0.0.1:80".into_target_addr()?);
+        tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(handle.join().unwrap(), b"\x04\x01\x00\x50\x0a\0\0\x01me\0");
+        Ok(())
+    }
+
+    #[test]
+    fn connect_auto_falls_back_to_socks4() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let handle = thread::spawn(move || {
+            let mut buf = [0; 1024];
+            // A SOCKS4 server which does not understand the SOCKS5 greeting.
+            let (mut tcp, _) = listener.accept().unwrap();
+            let _ = tcp.read(&mut buf);
+            drop(tcp);
+            let (mut tcp, _) = listener.accept().unwrap();
+            let n = tcp.read(&mut buf).unwrap();
+            tcp.write_all(b"\x00\x5a\0\0\0\0\0\0").unwrap();
+            buf[..n].to_vec()
+        });
+        let conn = SocksStream::connect_auto(addr, "10.0.0.1:80")?;
+        let stream = tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(handle.join().unwrap(), b"\x04\x01\x00\x50\x0a\0\0\x01\0");
+        assert_eq!(stream.version(), Version::V4);
+        match stream {
+            SocksStream::V4(stream) => assert_eq!(stream.proxy_addr(), addr),
+            SocksStream::V5(_) => panic!("SOCKS5 should have failed"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn connect_auto_does_not_fall_back_after_greeting() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            // A SOCKS5 server which closes the connection after reading the request. The
+            // listener is closed first, so a fallback fails to connect.
+            let (mut tcp, _) = listener.accept().unwrap();
+            drop(listener);
+            let _ = tcp.read(&mut buf);
+            tcp.write_all(b"\x05\x00").unwrap();
+            let _ = tcp.read(&mut buf);
+        });
+        let conn = SocksStream::connect_auto(addr, "10.0.0.1:80")?;
+        match tokio::runtime::current_thread::block_on_all(conn) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn connect_auto_without_fallback_fails() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        thread::spawn(move || {
+            let (mut tcp, _) = listener.accept().unwrap();
+            let _ = tcp.read(&mut [0; 16]);
+            tcp.write_all(b"\x00\x5a").unwrap();
+        });
+        let conn = SocksStream::connect_auto(addr, "10.0.0.1:80")?.with_fallback(false);
+        match tokio::runtime::current_thread::block_on_all(conn) {
+            Err(Error::InvalidResponseVersion {
+                expected: 0x05,
+                found: 0x00,
+            }) => Ok(()),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn invalid_targets_should_fail() {
+        assert!(Socks4Stream::connect("127.0.0.1:1080", "[::1]:80").is_err());
+        assert!(Socks4Stream::connect("127.0.0.1:1080", ("a\0b", 80)).is_err());
+        assert!(
+            Socks4Stream::connect_with_userid("127.0.0.1:1080", "10.0.0.1:80", "a\0b").is_err()
+        );
+    }
+
+    #[test]
+    fn port_zero_is_rejected_unless_allowed() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let conn = Socks4Stream::connect(closed, "10.0.0.1:0")?;
+        match tokio::runtime::current_thread::block_on_all(conn) {
+            Err(Error::InvalidTargetAddress(_)) => {}
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        let (addr, handle) = proxy(b"\x00\x5a\0\0\0\0\0\0");
+        let conn = Socks4Stream::connect(addr, "10.0.0.1:0")?.allow_port_zero();
+        tokio::runtime::current_thread::block_on_all(conn)?;
+        assert_eq!(handle.join().unwrap(), b"\x04\x01\x00\x00\x0a\0\0\x01\0");
+        Ok(())
+    }
+}
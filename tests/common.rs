@@ -1,6 +1,6 @@
 use once_cell::sync::OnceCell;
 use std::net::{SocketAddr, TcpStream as StdTcpStream};
-use std::sync::Mutex;
+use std::sync::{mpsc, Mutex};
 use tokio::{
     io::{copy, read_exact, write_all},
     net::TcpListener,
@@ -8,8 +8,8 @@ use tokio::{
     runtime::Runtime,
 };
 use tokio_socks::{
-    tcp::{BindFuture, ConnectFuture},
-    Error,
+    tcp::{ConnectFuture, Socks5Listener},
+    Error, TargetAddr,
 };
 
 type Result<T> = std::result::Result<T, Error>;
@@ -46,15 +46,29 @@ where
     Ok(())
 }
 
-pub fn test_bind<S>(bind: BindFuture<'static, 'static, S>) -> Result<()>
+#[cfg(feature = "blocking")]
+#[allow(dead_code)]
+pub fn test_blocking_connect(mut stream: tokio_socks::blocking::Socks5Stream) -> Result<()> {
+    use std::io::{Read, Write};
+
+    stream.write_all(MSG)?;
+    let mut res = [0; 5];
+    stream.read_exact(&mut res)?;
+    assert_eq!(&res[..], MSG);
+    Ok(())
+}
+
+pub fn test_bind<F>(bind: F) -> Result<()>
 where
-    S: Stream<Item = SocketAddr, Error = Error> + Send + 'static,
+    F: Future<Item = Socks5Listener, Error = Error> + Send + 'static,
 {
-    let fut = bind.and_then(|bind| {
+    let (peer_tx, peer_rx) = mpsc::channel();
+    let fut = bind.and_then(move |bind| {
         let bind_addr = bind.bind_addr().to_owned();
         tokio::spawn(
             bind.accept()
-                .and_then(|tcp| {
+                .and_then(move |tcp| {
+                    peer_tx.send(tcp.target_addr().to_owned()).unwrap();
                     let (reader, writer) = tcp.split();
                     copy(reader, writer).map(|_| ()).map_err(Into::into)
                 })
@@ -69,6 +83,8 @@ where
     let mut buf = [0; 5];
     tcp.read_exact(&mut buf[..])?;
     assert_eq!(&buf[..], MSG);
+    let peer_addr = peer_rx.recv().unwrap();
+    assert_eq!(peer_addr, TargetAddr::Ip(tcp.local_addr()?));
     Ok(())
 }
 
@@ -15,7 +15,7 @@ fi
 for test in ${list}; do
     3proxy ${dir}/${test}.cfg
 
-    cargo test --test ${test}
+    cargo test --all-features --test ${test}
     test_exit_code=$?
 
     pkill -F /tmp/3proxy-test.pid
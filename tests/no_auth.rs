@@ -1,9 +1,26 @@
 mod common;
 
-use common::{test_bind, test_connect, ECHO_SERVER_ADDR, PROXY_ADDR};
+use common::{runtime, test_bind, test_connect, ECHO_SERVER_ADDR, MSG, PROXY_ADDR};
+use std::io::{Read, Write};
+use std::net::TcpStream as StdTcpStream;
+use std::time::Duration;
+use tokio::{
+    codec::LinesCodec,
+    io::{read_exact, write_all},
+    net::{TcpListener, TcpStream},
+    prelude::*,
+};
+#[cfg(feature = "udp")]
+use tokio_socks::udp::Socks5Datagram;
+#[cfg(feature = "v4")]
+use tokio_socks::v4::Socks4Stream;
 use tokio_socks::{
-    tcp::{Socks5Listener, Socks5Stream},
-    Error,
+    auth::Method,
+    chain::ProxyChain,
+    connector::{DynConnector, ProxyConnect, Socks5Connector},
+    forward::forward_tcp,
+    tcp::{handshake_only, NegotiatedAuth, Socks5Listener, Socks5Stream},
+    Error, IntoTargetAddr, TargetAddr,
 };
 
 type Result<T> = std::result::Result<T, Error>;
@@ -14,8 +31,280 @@ fn connect() -> Result<()> {
     test_connect(conn)
 }
 
+#[cfg(feature = "blocking")]
+#[test]
+fn blocking_connect() -> Result<()> {
+    // Starts the echo server.
+    runtime();
+    let stream = tokio_socks::blocking::Socks5Stream::connect(PROXY_ADDR, ECHO_SERVER_ADDR)?;
+    common::test_blocking_connect(stream)
+}
+
+#[cfg(feature = "v4")]
+#[test]
+fn socks4_connect() -> Result<()> {
+    let fut = Socks4Stream::connect(PROXY_ADDR, "127.0.0.1:10007")?
+        .and_then(|tcp| write_all(tcp, MSG).map_err(Into::into))
+        .and_then(|(tcp, _)| read_exact(tcp, [0; 5]).map_err(Into::into))
+        .map(|(_, v)| v);
+    let res = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], MSG);
+    Ok(())
+}
+
+#[cfg(feature = "v4")]
+#[test]
+fn socks4a_connect() -> Result<()> {
+    let fut = Socks4Stream::connect(PROXY_ADDR, ECHO_SERVER_ADDR)?
+        .and_then(|tcp| write_all(tcp, MSG).map_err(Into::into))
+        .and_then(|(tcp, _)| read_exact(tcp, [0; 5]).map_err(Into::into))
+        .map(|(_, v)| v);
+    let res = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], MSG);
+    Ok(())
+}
+
+#[cfg(feature = "udp")]
+#[test]
+fn udp_associate() -> Result<()> {
+    let echo = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    let echo_addr = echo.local_addr()?;
+    std::thread::spawn(move || {
+        let mut buf = [0; 64];
+        let (n, from) = echo.recv_from(&mut buf).unwrap();
+        echo.send_to(&buf[..n], from).unwrap();
+    });
+
+    let fut = Socks5Datagram::bind(PROXY_ADDR, &"127.0.0.1:0".parse().unwrap())?
+        .and_then(move |datagram| datagram.send_dgram(MSG, TargetAddr::Ip(echo_addr)))
+        .and_then(|(datagram, _)| datagram.recv_dgram([0; 16]))
+        .map(|(_, buf, len, from)| (buf[..len].to_vec(), from));
+    let (res, from) = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], MSG);
+    assert_eq!(from, TargetAddr::Ip(echo_addr));
+    Ok(())
+}
+
+#[cfg(feature = "udp")]
+#[test]
+fn udp_demux() -> Result<()> {
+    let echo_once = || -> Result<std::net::SocketAddr> {
+        let echo = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        let addr = echo.local_addr()?;
+        std::thread::spawn(move || {
+            let mut buf = [0; 64];
+            let (n, from) = echo.recv_from(&mut buf).unwrap();
+            echo.send_to(&buf[..n], from).unwrap();
+        });
+        Ok(addr)
+    };
+    let (first, second) = (echo_once()?, echo_once()?);
+
+    let fut = Socks5Datagram::bind(PROXY_ADDR, &"127.0.0.1:0".parse().unwrap())?
+        .and_then(move |datagram| datagram.send_dgram(b"first", TargetAddr::Ip(first)))
+        .and_then(move |(datagram, _)| datagram.send_dgram(b"second", TargetAddr::Ip(second)))
+        .and_then(move |(datagram, _)| {
+            let mut demux = datagram.demux();
+            let peer = demux
+                .register(TargetAddr::Ip(first))
+                .map(move |data| (data, TargetAddr::Ip(first)))
+                .map_err(|_| unreachable!());
+            // The registered peer only shows up through its channel.
+            peer.select(demux).take(2).collect()
+        });
+    let mut res = runtime().lock().unwrap().block_on(fut)?;
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        res,
+        vec![
+            (b"first".to_vec(), TargetAddr::Ip(first)),
+            (b"second".to_vec(), TargetAddr::Ip(second)),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn connect_with_initial_data() -> Result<()> {
+    let fut = Socks5Stream::connect(PROXY_ADDR, ECHO_SERVER_ADDR)?
+        .with_initial_data(MSG)
+        .and_then(|tcp| read_exact(tcp, [0; 5]).map_err(Into::into))
+        .map(|(_, v)| v);
+    let res = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], MSG);
+    Ok(())
+}
+
+#[test]
+fn close() -> Result<()> {
+    let fut = Socks5Stream::connect(PROXY_ADDR, ECHO_SERVER_ADDR)?
+        .and_then(|tcp| write_all(tcp, MSG).map_err(Into::into))
+        .and_then(|(tcp, _)| tcp.close());
+    runtime().lock().unwrap().block_on(fut)
+}
+
 #[test]
 fn bind() -> Result<()> {
     let bind = Socks5Listener::bind(PROXY_ADDR, ECHO_SERVER_ADDR)?;
     test_bind(bind)
 }
+
+#[test]
+fn reconnect() -> Result<()> {
+    let conn = Socks5Stream::connect(PROXY_ADDR, ECHO_SERVER_ADDR)?;
+    let tcp = runtime().lock().unwrap().block_on(conn)?;
+    test_connect(tcp.reconnect()?)
+}
+
+#[test]
+fn sink_stream() -> Result<()> {
+    let fut = Socks5Stream::connect(PROXY_ADDR, ECHO_SERVER_ADDR)?
+        .and_then(|tcp| {
+            let (sink, stream) = tcp.into_sink_stream(LinesCodec::new());
+            sink.send("hello".to_owned())
+                .and_then(|_| stream.into_future().map_err(|(e, _)| e))
+                .map_err(Into::into)
+        })
+        .map(|(line, _)| line);
+    let line = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(line, Some("hello".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn dyn_connector() -> Result<()> {
+    let connector: DynConnector = Box::new(Socks5Connector::new(PROXY_ADDR.parse().unwrap()));
+    let fut = connector
+        .connect(ECHO_SERVER_ADDR.into_target_addr()?)
+        .and_then(|stream| write_all(stream, MSG).map_err(Into::into))
+        .and_then(|(stream, _)| read_exact(stream, [0; 5]).map_err(Into::into))
+        .map(|(_, v)| v);
+    let res = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], MSG);
+    Ok(())
+}
+
+#[test]
+fn connector_resolving_locally() -> Result<()> {
+    let connector = Socks5Connector::new(PROXY_ADDR.parse().unwrap()).resolve_locally(true);
+    let fut = connector
+        .connect(ECHO_SERVER_ADDR.into_target_addr()?)
+        .and_then(|stream| write_all(stream, MSG).map_err(Into::into))
+        .and_then(|(stream, _)| read_exact(stream, [0; 5]).map_err(Into::into))
+        .map(|(_, v)| v);
+    let res = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], MSG);
+    Ok(())
+}
+
+#[test]
+fn bind_into_parts() -> Result<()> {
+    let bind = Socks5Listener::bind(PROXY_ADDR, ECHO_SERVER_ADDR)?.map(|listener| {
+        listener
+            .set_keepalive(Some(Duration::from_secs(10)))
+            .unwrap();
+        assert_eq!(listener.keepalive().unwrap(), Some(Duration::from_secs(10)));
+        let (tcp, info) = listener.into_parts();
+        assert_eq!(
+            info.requested_target,
+            ECHO_SERVER_ADDR.into_target_addr().unwrap()
+        );
+        Socks5Listener::from_parts(tcp, info)
+    });
+    test_bind(bind)
+}
+
+#[test]
+fn forward() -> Result<()> {
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap())?;
+    let local_addr = listener.local_addr()?;
+    let connector = Socks5Connector::new(PROXY_ADDR.parse().unwrap());
+    let fut = forward_tcp(listener, connector, ECHO_SERVER_ADDR)?;
+    runtime().lock().unwrap().spawn(fut.map_err(|_| ()));
+    let mut tcp = StdTcpStream::connect(local_addr)?;
+    tcp.write_all(MSG)?;
+    let mut buf = [0; 5];
+    tcp.read_exact(&mut buf[..])?;
+    assert_eq!(&buf[..], MSG);
+    Ok(())
+}
+
+#[cfg(feature = "udp")]
+#[test]
+fn forward_udp() -> Result<()> {
+    let echo = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    let echo_addr = echo.local_addr()?;
+    std::thread::spawn(move || {
+        let mut buf = [0; 64];
+        for _ in 0..2 {
+            let (n, from) = echo.recv_from(&mut buf).unwrap();
+            echo.send_to(&buf[..n], from).unwrap();
+        }
+    });
+    let local = tokio::net::UdpSocket::bind(&"127.0.0.1:0".parse().unwrap())?;
+    let local_addr = local.local_addr()?;
+    let fut = tokio_socks::forward::forward_udp(local, PROXY_ADDR, echo_addr)?;
+    runtime().lock().unwrap().spawn(fut.map_err(|_| ()));
+
+    // The second socket stands for an application which has rebound its socket.
+    for _ in 0..2 {
+        let client = std::net::UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        client.send_to(MSG, local_addr)?;
+        let mut buf = [0; 16];
+        let (n, from) = client.recv_from(&mut buf)?;
+        assert_eq!(&buf[..n], MSG);
+        assert_eq!(from, local_addr);
+    }
+    Ok(())
+}
+
+#[test]
+fn handshake_on_established_connection() -> Result<()> {
+    let fut = TcpStream::connect(&PROXY_ADDR.parse().unwrap())
+        .map_err(Into::into)
+        .and_then(|tcp| handshake_only(tcp, ECHO_SERVER_ADDR, Method::None))
+        .flatten()
+        .and_then(|(tcp, info)| {
+            assert_eq!(info.auth, NegotiatedAuth::None);
+            write_all(tcp, MSG).map_err(Into::into)
+        })
+        .and_then(|(tcp, _)| read_exact(tcp, [0; 5]).map_err(Into::into))
+        .map(|(_, v)| v);
+    let res = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], MSG);
+    Ok(())
+}
+
+#[test]
+fn map_target() -> Result<()> {
+    let connector =
+        Socks5Connector::new(PROXY_ADDR.parse().unwrap()).map_target(|target| match target {
+            TargetAddr::Domain(ref domain, _) if domain == "echo.invalid" => {
+                ECHO_SERVER_ADDR.into_target_addr().unwrap()
+            }
+            target => target,
+        });
+    let fut = connector
+        .connect("echo.invalid:1".into_target_addr()?)
+        .and_then(|stream| write_all(stream, MSG).map_err(Into::into))
+        .and_then(|(stream, _)| read_exact(stream, [0; 5]).map_err(Into::into))
+        .map(|(_, v)| v);
+    let res = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], MSG);
+    Ok(())
+}
+
+#[test]
+fn proxy_chain() -> Result<()> {
+    let proxy = PROXY_ADDR.parse().unwrap();
+    let chain = ProxyChain::new(proxy).hop(TargetAddr::Ip(proxy));
+    let fut = chain
+        .connect(ECHO_SERVER_ADDR.into_target_addr()?)
+        .and_then(|stream| write_all(stream, MSG).map_err(Into::into))
+        .and_then(|(stream, _)| read_exact(stream, [0; 5]).map_err(Into::into))
+        .map(|(_, v)| v);
+    let res = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], MSG);
+    Ok(())
+}
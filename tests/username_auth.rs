@@ -1,9 +1,14 @@
 mod common;
 
-use common::{test_bind, test_connect, ECHO_SERVER_ADDR, PROXY_ADDR};
+use common::{runtime, test_bind, test_connect, ECHO_SERVER_ADDR, MSG, PROXY_ADDR};
+use tokio::{
+    io::{read_exact, write_all},
+    prelude::*,
+};
 use tokio_socks::{
+    connector::{Overrides, Socks5Connector},
     tcp::{Socks5Listener, Socks5Stream},
-    Error,
+    Error, IntoTargetAddr,
 };
 
 type Result<T> = std::result::Result<T, Error>;
@@ -15,9 +20,112 @@ fn connect() -> Result<()> {
     test_connect(conn)
 }
 
+#[cfg(feature = "blocking")]
+#[test]
+fn blocking_connect() -> Result<()> {
+    // Starts the echo server.
+    runtime();
+    let stream = tokio_socks::blocking::Socks5Stream::connect_with_password(
+        PROXY_ADDR,
+        ECHO_SERVER_ADDR,
+        "mylogin",
+        "mypassword",
+    )?;
+    common::test_blocking_connect(stream)
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn blocking_connect_with_password_bytes() -> Result<()> {
+    // Starts the echo server.
+    runtime();
+    let stream = tokio_socks::blocking::Socks5Stream::connect_with_password_bytes(
+        PROXY_ADDR,
+        ECHO_SERVER_ADDR,
+        b"mylogin",
+        b"mypassword",
+    )?;
+    common::test_blocking_connect(stream)
+}
+
+#[cfg(feature = "udp")]
+#[test]
+fn udp_associate_with_password_bytes() -> Result<()> {
+    use tokio_socks::{udp::Socks5Datagram, TargetAddr};
+
+    let echo = std::net::UdpSocket::bind("127.0.0.1:0")?;
+    let echo_addr = echo.local_addr()?;
+    std::thread::spawn(move || {
+        let mut buf = [0; 64];
+        let (n, from) = echo.recv_from(&mut buf).unwrap();
+        echo.send_to(&buf[..n], from).unwrap();
+    });
+
+    let fut = Socks5Datagram::bind_with_password_bytes(
+        PROXY_ADDR,
+        &"127.0.0.1:0".parse().unwrap(),
+        b"mylogin",
+        b"mypassword",
+    )?
+    .and_then(move |datagram| datagram.send_dgram(common::MSG, TargetAddr::Ip(echo_addr)))
+    .and_then(|(datagram, _)| datagram.recv_dgram([0; 16]))
+    .map(|(_, buf, len, _)| buf[..len].to_vec());
+    let res = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], common::MSG);
+    Ok(())
+}
+
 #[test]
 fn bind() -> Result<()> {
     let bind =
         Socks5Listener::bind_with_password(PROXY_ADDR, ECHO_SERVER_ADDR, "mylogin", "mypassword")?;
     test_bind(bind)
 }
+
+#[test]
+fn reconnect() -> Result<()> {
+    let conn =
+        Socks5Stream::connect_with_password(PROXY_ADDR, ECHO_SERVER_ADDR, "mylogin", "mypassword")?;
+    let tcp = runtime().lock().unwrap().block_on(conn)?;
+    test_connect(tcp.reconnect_with_password("mylogin", "mypassword")?)
+}
+
+#[test]
+fn connect_with_password_bytes() -> Result<()> {
+    let conn = Socks5Stream::connect_with_password_bytes(
+        PROXY_ADDR,
+        ECHO_SERVER_ADDR,
+        b"mylogin",
+        b"mypassword",
+    )?;
+    test_connect(conn)
+}
+
+#[test]
+fn bind_with_password_bytes() -> Result<()> {
+    let bind = Socks5Listener::bind_with_password_bytes(
+        PROXY_ADDR,
+        ECHO_SERVER_ADDR,
+        b"mylogin",
+        b"mypassword",
+    )?;
+    test_bind(bind)
+}
+
+#[test]
+fn connector_with_overridden_credentials() -> Result<()> {
+    let connector =
+        Socks5Connector::with_password(PROXY_ADDR.parse().unwrap(), "mylogin", "wrongpassword");
+    let overrides = Overrides {
+        credentials: Some((b"mylogin".to_vec(), b"mypassword".to_vec())),
+        ..Overrides::default()
+    };
+    let fut = connector
+        .connect_with(ECHO_SERVER_ADDR.into_target_addr()?, overrides)
+        .and_then(|stream| write_all(stream, MSG).map_err(Into::into))
+        .and_then(|(stream, _)| read_exact(stream, [0; 5]).map_err(Into::into))
+        .map(|(_, v)| v);
+    let res = runtime().lock().unwrap().block_on(fut)?;
+    assert_eq!(&res[..], MSG);
+    Ok(())
+}